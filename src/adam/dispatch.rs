@@ -0,0 +1,485 @@
+//===Adam request dispatcher===
+//Each module gets its own thread and bounded queue so requests to a module are sent in order and a module that
+//hangs only holds up its own triggers.
+#[cfg(not(test))]
+use log::{error, info, warn};
+
+#[cfg(test)]
+use std::{println as info, println as warn, println as error};
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::rc::Rc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::outcomes::TriggerOutcome;
+use super::{report, send_with_retries, AdamID, AdamRequest, Reporting};
+
+///The state of one module's queue, shared with the web server
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct QueueStats {
+    ///Requests waiting to be sent
+    pub depth: usize,
+    pub peak_depth: usize,
+    pub dispatched: u64,
+    ///Requests dropped because the queue was full, or to make room for one that switches outputs off
+    pub dropped_full: u64,
+    ///Requests that switch outputs off queued past `queue_capacity` because nothing else could make room
+    pub overflowed: u64,
+    ///Requests dropped because they were older than `stale_after_ms` when their turn came
+    pub dropped_late: u64,
+    ///How long the last request waited in the queue
+    pub last_wait_ms: u64,
+    pub max_wait_ms: u64,
+}
+
+///The queue of every module
+#[derive(Default)]
+pub struct DispatchStats {
+    modules: Mutex<BTreeMap<AdamID, QueueStats>>,
+}
+impl DispatchStats {
+    pub fn snapshot(&self) -> BTreeMap<AdamID, QueueStats> {
+        self.modules.lock().unwrap().clone()
+    }
+    fn update(&self, module: AdamID, change: impl FnOnce(&mut QueueStats)) {
+        change(self.modules.lock().unwrap().entry(module).or_default())
+    }
+}
+
+struct Queued {
+    at: Instant,
+    request: AdamRequest,
+}
+///Switching pulsed outputs back off once their pulse is over
+struct Release {
+    due: Instant,
+    request: Rc<AdamRequest>,
+    outputs: Vec<(u8, bool)>,
+}
+
+///One module's queue, shared by the dispatcher and the module's thread
+#[derive(Default)]
+struct ModuleQueue {
+    waiting: Mutex<Waiting>,
+    ready: Condvar,
+}
+#[derive(Default)]
+struct Waiting {
+    requests: VecDeque<Queued>,
+    closed: bool,
+}
+///What happened to a request that was queued
+enum Queueing {
+    Queued,
+    ///The queue was full and the request was dropped
+    Full(Queued),
+    ///The queue was full so this older request was dropped to make room for one that switches outputs off
+    Replaced(Queued),
+    ///The queue was full of requests that switch outputs off, so it went past its capacity
+    Overflowed,
+}
+impl ModuleQueue {
+    ///Never waits for room, so a module that hangs can't hold up the adam loop
+    fn push(&self, queued: Queued, capacity: usize) -> Queueing {
+        let mut waiting = self.waiting.lock().unwrap();
+        let outcome = if waiting.requests.len() < capacity {
+            Queueing::Queued
+        } else if !queued.request.must_send {
+            return Queueing::Full(queued);
+        } else {
+            match waiting.requests.iter().position(|waiting| !waiting.request.must_send) {
+                Some(oldest) => Queueing::Replaced(waiting.requests.remove(oldest).unwrap()),
+                None => Queueing::Overflowed,
+            }
+        };
+        waiting.requests.push_back(queued);
+        self.ready.notify_one();
+        outcome
+    }
+    ///The next request, waiting for one until `until`. Requests still queued are taken before it reports closing
+    fn take(&self, until: Option<Instant>) -> Result<Queued, RecvTimeoutError> {
+        let mut waiting = self.waiting.lock().unwrap();
+        loop {
+            if let Some(queued) = waiting.requests.pop_front() {
+                return Ok(queued);
+            }
+            if waiting.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            waiting = match until {
+                None => self.ready.wait(waiting).unwrap(),
+                Some(until) => {
+                    let now = Instant::now();
+                    if now >= until {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.ready.wait_timeout(waiting, until - now).unwrap().0
+                }
+            };
+        }
+    }
+    fn close(&self) {
+        self.waiting.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+}
+
+///Sends requests to the queue of the module they are for, starting its thread the first time
+pub(super) struct Dispatcher {
+    queues: HashMap<AdamID, (Arc<ModuleQueue>, JoinHandle<()>)>,
+    reporting: Reporting,
+    stats: Arc<DispatchStats>,
+}
+impl Dispatcher {
+    pub fn new(reporting: Reporting, stats: Arc<DispatchStats>) -> Dispatcher {
+        Dispatcher {
+            queues: HashMap::new(),
+            reporting,
+            stats,
+        }
+    }
+    pub fn send(&mut self, request: AdamRequest) {
+        let module = request.module;
+        let capacity = request.unit.module.timing.queue_capacity.max(1);
+        let (reporting, stats) = (&self.reporting, &self.stats);
+        let (queue, _) = self.queues.entry(module).or_insert_with(|| {
+            let queue = Arc::new(ModuleQueue::default());
+            let (receiver, reporting, stats) = (queue.clone(), reporting.clone(), stats.clone());
+            let worker = thread::spawn(move || run_queue(&receiver, reporting, stats));
+            (queue, worker)
+        });
+        let queued = Queued {
+            at: Instant::now(),
+            request,
+        };
+        //the module's thread only lets go of the queue if it panicked
+        if Arc::strong_count(queue) == 1 {
+            return self.drop_request(queued, "dropped, the module's queue has stopped".to_string());
+        }
+        //counted before queueing so the worker never takes the depth below zero
+        self.stats.update(module, |s| {
+            s.depth += 1;
+            s.peak_depth = s.peak_depth.max(s.depth);
+        });
+        match queue.push(queued, capacity) {
+            Queueing::Queued => (),
+            Queueing::Full(queued) => {
+                self.stats.update(module, |s| {
+                    s.depth -= 1;
+                    s.dropped_full += 1;
+                });
+                self.drop_request(queued, format!("dropped, the queue of {:} requests was full", capacity));
+            }
+            Queueing::Replaced(oldest) => {
+                self.stats.update(module, |s| {
+                    s.depth -= 1;
+                    s.dropped_full += 1;
+                });
+                self.drop_request(oldest, "dropped to make room for a request that switches outputs off".to_string());
+            }
+            Queueing::Overflowed => {
+                warn!("{{Adam}}The queue of module {:} is full of requests that switch outputs off, queueing one more", module);
+                self.stats.update(module, |s| s.overflowed += 1);
+            }
+        }
+    }
+    fn drop_request(&self, queued: Queued, reason: String) {
+        error!("{{Adam}}Request for module {:} {:}", queued.request.module, reason);
+        report(&self.reporting, &dropped(&queued.request, reason));
+    }
+    ///Stops taking requests and waits for every queue to finish, including pulses that are still on
+    pub fn close(self) {
+        for (module, (queue, worker)) in self.queues {
+            queue.close();
+            if worker.join().is_err() {
+                error!("{{Adam}}The queue of module {:} panicked", module);
+            }
+        }
+    }
+}
+
+///Sends the requests of one module in the order they were queued.
+///Pulses are released in between requests rather than holding up the queue.
+fn run_queue(queue: &ModuleQueue, reporting: Reporting, stats: Arc<DispatchStats>) {
+    let mut releases: Vec<Release> = Vec::new();
+    loop {
+        let next = queue.take(releases.first().map(|release| release.due));
+        //a release that is due goes before a newer trigger on the same output
+        send_due_releases(&mut releases, Instant::now(), &reporting);
+        match next {
+            Ok(queued) => {
+                releases.extend(trigger(queued, &reporting, &stats));
+                releases.sort_by_key(|release| release.due);
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    //nothing is left switched on when the dispatcher closes
+    while let Some(release) = releases.first() {
+        thread::sleep(release.due.saturating_duration_since(Instant::now()));
+        send_due_releases(&mut releases, Instant::now(), &reporting);
+    }
+}
+fn send_due_releases(releases: &mut Vec<Release>, now: Instant, reporting: &Reporting) {
+    let due = releases.iter().take_while(|release| release.due <= now).count();
+    for release in releases.drain(..due) {
+        //releases are always retried fully so nothing is left switched on
+        let outcome = send_with_retries(&release.request, &release.outputs, None, true);
+        report(reporting, &outcome);
+    }
+}
+///Sends a request unless it is too late to be useful. Returns the releases of its pulses
+fn trigger(queued: Queued, reporting: &Reporting, stats: &DispatchStats) -> Vec<Release> {
+    let Queued { at, request } = queued;
+    let wait_ms = at.elapsed().as_millis() as u64;
+    stats.update(request.module, |s| {
+        s.depth -= 1;
+        s.last_wait_ms = wait_ms;
+        s.max_wait_ms = s.max_wait_ms.max(wait_ms);
+    });
+    let timing = &request.unit.module.timing;
+    let age = SystemTime::now().duration_since(request.received).unwrap_or_default();
+    let stale = age > Duration::from_millis(timing.stale_after_ms);
+    if stale && request.must_send {
+        warn!(
+            "{{Adam}}Request {:?} to module {:} is {:}ms late but switches outputs off, sending it anyway",
+            request.outputs,
+            request.module,
+            age.as_millis()
+        );
+    } else if stale {
+        let reason = format!(
+            "dropped, it was {:}ms after the event which is over stale_after_ms ({:})",
+            age.as_millis(),
+            timing.stale_after_ms
+        );
+        warn!("{{Adam}}Request {:?} to module {:} {:}", request.outputs, request.module, reason);
+        stats.update(request.module, |s| s.dropped_late += 1);
+        report(reporting, &dropped(&request, reason));
+        return Vec::new();
+    }
+    info!("{{Adam}} Sending Request to module {:} | {:?}", request.module, request.outputs);
+    //There is no point retrying once the shortest pulse in the request would have finished
+    let deadline = request
+        .releases
+        .first()
+        .map(|(after, _)| Instant::now() + *after);
+    let outcome = send_with_retries(&request, &request.outputs, deadline, false);
+    report(reporting, &outcome);
+    stats.update(request.module, |s| s.dispatched += 1);
+
+    //Pulsed outputs are switched back once their pulse has been on for long enough
+    let on_at = Instant::now();
+    let request = Rc::new(request);
+    request
+        .releases
+        .iter()
+        .map(|(after, outputs)| Release {
+            due: on_at + *after,
+            request: request.clone(),
+            outputs: outputs.clone(),
+        })
+        .collect()
+}
+///The outcome of a request that was never sent
+fn dropped(request: &AdamRequest, reason: String) -> TriggerOutcome {
+    TriggerOutcome {
+        module: request.module,
+        host: request.unit.module.host.clone(),
+        ports: request.ports.clone(),
+        outputs: request.outputs.clone(),
+        release: false,
+        unix_time_ms: crate::events::unix_time_ms(SystemTime::now()),
+        latency_ms: 0,
+        frame_latency_ms: None,
+        attempts: 0,
+        status: None,
+        error: Some(reason),
+    }
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::MockAdam;
+    use super::super::outcomes::TriggerLog;
+    use super::super::{AdamModule, AdamUnit, RequestTiming};
+    use super::*;
+    use crate::vdcp::types::{PortAlarmMap, PortAlarms};
+    use std::net::TcpListener;
+    use std::sync::atomic::Ordering;
+
+    fn request(module: AdamID, port: u16, timing: RequestTiming, received: SystemTime) -> AdamRequest {
+        let unit = AdamUnit::load(&AdamModule {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            timing,
+            health_poll_ms: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        AdamRequest {
+            module,
+            unit,
+            ports: vec![1],
+            received,
+            outputs: vec![(0, true)],
+            releases: vec![(Duration::from_millis(20), vec![(0, false)])],
+            must_send: false,
+        }
+    }
+    fn reporting() -> Reporting {
+        let mut alarms = PortAlarmMap::new();
+        alarms.insert(1, Arc::new(PortAlarms::default()));
+        Reporting {
+            log: Arc::new(TriggerLog::default()),
+            alarms,
+        }
+    }
+
+    #[test]
+    fn hung_module_only_holds_up_its_own_queue() {
+        //accepts connections but never answers
+        let hung = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hung_port = hung.local_addr().unwrap().port();
+        let adam = MockAdam::start("working", "127.0.0.1:0".parse().unwrap(), "root", "admin").unwrap();
+        let slow = RequestTiming {
+            read_timeout_ms: 300,
+            retries: 0,
+            ..Default::default()
+        };
+        let stats = Arc::new(DispatchStats::default());
+        let mut dispatcher = Dispatcher::new(reporting(), stats.clone());
+
+        let start = Instant::now();
+        dispatcher.send(request(0, hung_port, slow.clone(), SystemTime::now()));
+        dispatcher.send(request(0, hung_port, slow, SystemTime::now()));
+        dispatcher.send(request(1, adam.address.port(), RequestTiming::default(), SystemTime::now()));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(adam.history().len(), 2, "the working module got its pulse straight away");
+        assert!(start.elapsed() < Duration::from_millis(300));
+        assert_eq!(stats.snapshot()[&0].depth, 1);
+
+        dispatcher.close();
+        let stats = stats.snapshot();
+        assert_eq!((stats[&0].depth, stats[&0].dispatched), (0, 2));
+        assert!(stats[&0].max_wait_ms >= 250);
+        assert_eq!(stats[&1].dispatched, 1);
+    }
+
+    #[test]
+    fn stale_requests_are_dropped() {
+        let adam = MockAdam::start("stale", "127.0.0.1:0".parse().unwrap(), "root", "admin").unwrap();
+        let reporting = reporting();
+        let stats = Arc::new(DispatchStats::default());
+        let mut dispatcher = Dispatcher::new(reporting.clone(), stats.clone());
+        let timing = RequestTiming {
+            stale_after_ms: 500,
+            ..Default::default()
+        };
+        let late = SystemTime::now() - Duration::from_secs(1);
+        dispatcher.send(request(0, adam.address.port(), timing, late));
+        dispatcher.close();
+
+        assert!(adam.history().is_empty());
+        assert_eq!(stats.snapshot()[&0].dropped_late, 1);
+        let outcomes = reporting.log.recent();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].attempts, 0);
+        assert!(outcomes[0].error.as_ref().unwrap().contains("stale_after_ms"));
+        assert!(reporting.alarms[&1].output_failed.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn late_requests_that_switch_off_are_still_sent() {
+        let adam = MockAdam::start("late off", "127.0.0.1:0".parse().unwrap(), "root", "admin").unwrap();
+        let stats = Arc::new(DispatchStats::default());
+        let mut dispatcher = Dispatcher::new(reporting(), stats.clone());
+        let timing = RequestTiming {
+            stale_after_ms: 500,
+            ..Default::default()
+        };
+        let mut latch_off = request(0, adam.address.port(), timing, SystemTime::now() - Duration::from_secs(1));
+        latch_off.outputs = vec![(0, false)];
+        latch_off.releases = Vec::new();
+        latch_off.must_send = true;
+        dispatcher.send(latch_off);
+        dispatcher.close();
+
+        assert_eq!(adam.history().len(), 1);
+        assert_eq!(stats.snapshot()[&0].dropped_late, 0);
+    }
+
+    #[test]
+    fn full_queue_drops_new_requests() {
+        let hung = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hung_port = hung.local_addr().unwrap().port();
+        let timing = RequestTiming {
+            read_timeout_ms: 200,
+            retries: 0,
+            queue_capacity: 1,
+            ..Default::default()
+        };
+        let reporting = reporting();
+        let stats = Arc::new(DispatchStats::default());
+        let mut dispatcher = Dispatcher::new(reporting.clone(), stats.clone());
+        for _ in 0..3 {
+            dispatcher.send(request(0, hung_port, timing.clone(), SystemTime::now()));
+            //lets the worker take the first request off the queue
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(stats.snapshot()[&0].dropped_full, 1);
+        dispatcher.close();
+        assert_eq!(stats.snapshot()[&0].dispatched, 2);
+        assert_eq!(reporting.log.recent().iter().filter(|outcome| outcome.attempts == 0).count(), 1);
+    }
+
+    #[test]
+    fn switching_off_never_waits_for_a_module_that_never_answers() {
+        let hung = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hung_port = hung.local_addr().unwrap().port();
+        let adam = MockAdam::start("still working", "127.0.0.1:0".parse().unwrap(), "root", "admin").unwrap();
+        let timing = RequestTiming {
+            read_timeout_ms: 200,
+            retries: 0,
+            queue_capacity: 1,
+            ..Default::default()
+        };
+        let latch_off = || {
+            let mut latch_off = request(0, hung_port, timing.clone(), SystemTime::now());
+            latch_off.outputs = vec![(0, false)];
+            latch_off.releases = Vec::new();
+            latch_off.must_send = true;
+            latch_off
+        };
+        let stats = Arc::new(DispatchStats::default());
+        let mut dispatcher = Dispatcher::new(reporting(), stats.clone());
+        dispatcher.send(request(0, hung_port, timing.clone(), SystemTime::now()));
+        thread::sleep(Duration::from_millis(20));
+        dispatcher.send(request(0, hung_port, timing.clone(), SystemTime::now()));
+
+        let start = Instant::now();
+        //takes the place of the waiting pulse
+        dispatcher.send(latch_off());
+        //nothing left to drop, so it goes past the capacity
+        dispatcher.send(latch_off());
+        dispatcher.send(request(1, adam.address.port(), RequestTiming::default(), SystemTime::now()));
+        assert!(start.elapsed() < Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(adam.history().len(), 2, "the other module is unaffected");
+        let queue = &stats.snapshot()[&0];
+        assert_eq!((queue.depth, queue.dropped_full, queue.overflowed), (2, 1, 1));
+
+        dispatcher.close();
+        let queue = &stats.snapshot()[&0];
+        assert_eq!((queue.depth, queue.dispatched), (0, 3));
+    }
+}
@@ -0,0 +1,144 @@
+//===Adam module health monitoring===
+//Each module is polled in the background so an unreachable module shows up before a play fails on it.
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::events::unix_time_ms;
+use super::{modbus, rest, AdamID, AdamProtocol, AdamUnit, AdamUnits};
+
+///The last known state of one module
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ModuleHealth {
+    pub host: String,
+    ///None until the module has been polled once
+    pub online: Option<bool>,
+    ///When the module last answered a poll
+    pub last_seen_unix_ms: Option<u64>,
+    ///How long the last successful poll took
+    pub latency_ms: Option<u64>,
+    ///When the module last went online or offline
+    pub changed_unix_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+///The health of every module, shared with the web server
+#[derive(Default)]
+pub struct AdamHealth {
+    modules: Mutex<BTreeMap<AdamID, ModuleHealth>>,
+}
+impl AdamHealth {
+    pub fn snapshot(&self) -> BTreeMap<AdamID, ModuleHealth> {
+        self.modules.lock().unwrap().clone()
+    }
+    ///Records the result of a poll, logging when the module goes online or offline
+    fn update(&self, id: AdamID, host: &str, result: Result<Duration, String>, now: SystemTime) {
+        let now = unix_time_ms(now);
+        let mut modules = self.modules.lock().unwrap();
+        let health = modules.entry(id).or_default();
+        health.host = host.to_string();
+        let online = result.is_ok();
+        if health.online != Some(online) {
+            health.changed_unix_ms = Some(now);
+            match (&result, health.online) {
+                (Ok(_), _) => info!("{{Adam}}Module {:} ({:}) is online", id, host),
+                (Err(e), Some(true)) => warn!("{{Adam}}Module {:} ({:}) went offline: {:}", id, host, e),
+                (Err(e), _) => warn!("{{Adam}}Module {:} ({:}) is offline: {:}", id, host, e),
+            }
+        }
+        health.online = Some(online);
+        match result {
+            Ok(latency) => {
+                health.last_seen_unix_ms = Some(now);
+                health.latency_ms = Some(latency.as_millis() as u64);
+                health.last_error = None;
+            }
+            Err(e) => health.last_error = Some(e),
+        }
+    }
+}
+
+///Starts a thread per module that polls it every `health_poll_ms`. Modules with a poll time of 0 aren't polled.
+///The threads stop once `running` is dropped.
+pub fn start(units: &AdamUnits, health: Arc<AdamHealth>, running: Weak<()>) {
+    //modules that were removed from the config shouldn't be reported any more
+    health.modules.lock().unwrap().retain(|id, _| units.contains_key(id));
+    for (id, unit) in units {
+        let interval = Duration::from_millis(unit.module.health_poll_ms);
+        if interval == Duration::from_millis(0) {
+            continue;
+        }
+        let (id, unit, health, running) = (*id, unit.clone(), health.clone(), running.clone());
+        thread::spawn(move || {
+            while running.upgrade().is_some() {
+                let result = poll(&unit);
+                health.update(id, &unit.module.host, result, SystemTime::now());
+                thread::sleep(interval);
+            }
+        });
+    }
+}
+
+///Reads the outputs of the module and returns how long it took to answer
+fn poll(unit: &AdamUnit) -> Result<Duration, String> {
+    let started = Instant::now();
+    match &unit.module.protocol {
+        AdamProtocol::Rest => rest::read_outputs(unit).map(|_| ()).map_err(|e| e.message),
+        AdamProtocol::Modbus(settings) => modbus::read_coils(
+            &unit.module.host,
+            unit.module.port(),
+            &unit.module.timing,
+            settings,
+            0,
+            1,
+        )
+        .map(|_| ()),
+    }?;
+    Ok(started.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::MockAdam;
+    use super::super::AdamModule;
+    use super::*;
+
+    fn unit(port: u16) -> AdamUnit {
+        AdamUnit::load(&AdamModule {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn poll_mock_module() {
+        let mock = MockAdam::start("health", "127.0.0.1:0".parse().unwrap(), "root", "admin").unwrap();
+        assert!(poll(&unit(mock.address.port())).is_ok());
+        let closed_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        assert!(poll(&unit(closed_port)).is_err());
+    }
+    #[test]
+    fn transitions_are_tracked() {
+        let health = AdamHealth::default();
+        let at = |ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+        health.update(0, "a", Ok(Duration::from_millis(3)), at(1000));
+        health.update(0, "a", Ok(Duration::from_millis(4)), at(2000));
+        let state = &health.snapshot()[&0];
+        assert_eq!(state.online, Some(true));
+        assert_eq!(state.changed_unix_ms, Some(1000));
+        assert_eq!(state.last_seen_unix_ms, Some(2000));
+        assert_eq!(state.latency_ms, Some(4));
+
+        health.update(0, "a", Err("refused".to_string()), at(3000));
+        let state = &health.snapshot()[&0];
+        assert_eq!(state.online, Some(false));
+        assert_eq!(state.changed_unix_ms, Some(3000));
+        assert_eq!(state.last_seen_unix_ms, Some(2000));
+        assert_eq!(state.last_error.as_deref(), Some("refused"));
+    }
+}
@@ -0,0 +1,224 @@
+//===Mock adam module===
+//A tiny http server that behaves like the REST api of an ADAM-6000 module.
+//It is used by the tests and by `--simulate-adam` so pulse sequences can be checked without hardware.
+use log::*;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+///How many digital outputs the simulated module has
+pub const OUTPUT_COUNT: u8 = 8;
+///How many digital inputs the simulated module has
+pub const INPUT_COUNT: u8 = 12;
+
+///A single request that changed the outputs of the mock module
+#[derive(Clone, Debug)]
+pub struct OutputChange {
+    pub at: Instant,
+    pub outputs: Vec<(u8, bool)>,
+}
+
+struct MockState {
+    outputs: BTreeMap<u8, bool>,
+    inputs: BTreeMap<u8, bool>,
+    history: Vec<OutputChange>,
+}
+
+///Handle to a running mock module. The server keeps running after this is dropped.
+#[derive(Clone)]
+pub struct MockAdam {
+    pub address: SocketAddr,
+    name: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockAdam {
+    ///Starts a mock module listening on `bind`. Use port 0 to let the os pick a free port.
+    ///
+    ///Requests must use basic auth with `username` and `password` just like the real module.
+    pub fn start(name: &str, bind: SocketAddr, username: &str, password: &str) -> io::Result<MockAdam> {
+        let listener = TcpListener::bind(bind)?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            outputs: (0..OUTPUT_COUNT).map(|i| (i, false)).collect(),
+            inputs: (0..INPUT_COUNT).map(|i| (i, false)).collect(),
+            history: Vec::new(),
+        }));
+        let mock = MockAdam {
+            address,
+            name: name.to_string(),
+            state,
+        };
+        let auth = format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", username, password))
+        );
+        info!("{{MockAdam {:}}} listening on {:}", name, address);
+        let server = mock.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let server = server.clone();
+                        let auth = auth.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = server.handle_connection(stream, &auth) {
+                                warn!("{{MockAdam {:}}} failed handling request: {:}", server.name, e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("{{MockAdam {:}}} failed accepting connection: {:}", server.name, e),
+                }
+            }
+        });
+        Ok(mock)
+    }
+    ///The `host:port` to use in place of a real module's ip
+    pub fn host(&self) -> String {
+        self.address.to_string()
+    }
+    ///Current state of every output
+    pub fn outputs(&self) -> BTreeMap<u8, bool> {
+        self.state.lock().unwrap().outputs.clone()
+    }
+    ///Sets a digital input as if the wiring had changed
+    pub fn set_input(&self, number: u8, value: bool) {
+        self.state.lock().unwrap().inputs.insert(number, value);
+    }
+    ///Every change made to the outputs, oldest first
+    pub fn history(&self) -> Vec<OutputChange> {
+        self.state.lock().unwrap().history.clone()
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream, auth: &str) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("").to_string();
+        let path = parts.next().unwrap_or("").to_string();
+
+        let mut content_length = 0;
+        let mut authorized = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let mut header = line.splitn(2, ':');
+            let name = header.next().unwrap_or("").trim().to_ascii_lowercase();
+            let value = header.next().unwrap_or("").trim();
+            match name.as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "authorization" => authorized = value == auth,
+                _ => (),
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let (status, response) = match (authorized, method.as_str(), path.as_str()) {
+            (false, _, _) => {
+                warn!("{{MockAdam {:}}} rejected request with bad credentials", self.name);
+                ("401 Unauthorized", String::new())
+            }
+            (true, "GET", "/digitaloutput/all/value") => ("200 OK", self.outputs_xml()),
+            (true, "GET", "/digitalinput/all/value") => ("200 OK", self.inputs_xml()),
+            (true, "POST", "/digitaloutput/all/value") => {
+                match self.set_outputs(&String::from_utf8_lossy(&body)) {
+                    Ok(()) => ("200 OK", status_xml("OK")),
+                    Err(e) => {
+                        warn!("{{MockAdam {:}}} bad output request: {:}", self.name, e);
+                        ("400 Bad Request", status_xml("Fail"))
+                    }
+                }
+            }
+            _ => ("404 Not Found", String::new()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            response.len(),
+            response
+        )?;
+        stream.flush()
+    }
+    ///Applies a form body like `DO0=1&DO3=0`
+    fn set_outputs(&self, form: &str) -> Result<(), String> {
+        let outputs = form
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                let key = kv.next().unwrap_or("");
+                let value = kv.next().unwrap_or("");
+                let number: u8 = key
+                    .strip_prefix("DO")
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n < OUTPUT_COUNT)
+                    .ok_or_else(|| format!("unknown output '{:}'", key))?;
+                match value {
+                    "1" => Ok((number, true)),
+                    "0" => Ok((number, false)),
+                    _ => Err(format!("bad value '{:}' for {:}", value, key)),
+                }
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let since_last = state
+            .history
+            .last()
+            .map(|last| format!("{:?}", now.duration_since(last.at)))
+            .unwrap_or_else(|| "-".to_string());
+        info!(
+            "{{MockAdam {:}}} set outputs {:?} ({:} since last change)",
+            self.name, outputs, since_last
+        );
+        for (number, value) in &outputs {
+            state.outputs.insert(*number, *value);
+        }
+        state.history.push(OutputChange { at: now, outputs });
+        Ok(())
+    }
+    fn outputs_xml(&self) -> String {
+        values_xml("DO", &self.outputs())
+    }
+    fn inputs_xml(&self) -> String {
+        values_xml("DI", &self.state.lock().unwrap().inputs)
+    }
+}
+
+fn values_xml(element: &str, values: &BTreeMap<u8, bool>) -> String {
+    let values: String = values
+        .iter()
+        .map(|(id, value)| {
+            format!(
+                "<{0}><ID>{1:}</ID><VALUE>{2:}</VALUE></{0}>",
+                element, id, *value as u8
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" ?><ADAM-6050 status=\"OK\">{:}</ADAM-6050>",
+        values
+    )
+}
+
+fn status_xml(status: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" ?><ADAM-6050 status=\"{:}\"></ADAM-6050>",
+        status
+    )
+}
@@ -7,20 +7,88 @@ use log::{error, info, warn};
 #[cfg(test)]
 use std::{println as info, println as warn, println as error};
 
-use rayon::prelude::*;
-
 use serde::{Deserialize, Serialize};
-use std::sync::{mpsc::*, Arc};
+use serde_json::{json, Value};
+use std::sync::{atomic::{AtomicBool, Ordering}, mpsc::*, Arc, Mutex};
 use std::thread;
 use std::{self, io::Error};
-use std::{collections::HashMap };
-use std::{net::*, time::Duration};
-use ureq;
+use std::{collections::{BTreeMap, HashMap, HashSet} };
+use std::fmt::{self, Debug, Formatter};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+use crate::outputs::TriggerOutput;
+use crate::vdcp::types::{EventKind, PortAlarmMap, PortEvent};
+
+pub mod dispatch;
+pub mod health;
+pub mod mock;
+mod modbus;
+pub mod outcomes;
+mod rest;
+pub mod schedule;
+pub mod tally;
+
+pub use modbus::ModbusSettings;
+use dispatch::{DispatchStats, Dispatcher};
+use health::AdamHealth;
+use outcomes::{TriggerLog, TriggerOutcome};
+use schedule::{Schedule, Scheduler};
+use tally::{Tallies, TallyMapping};
+
+fn default_pulse_ms() -> u64 {
+    20
+}
+fn default_events() -> Vec<EventKind> {
+    vec![EventKind::Play]
+}
+
+///Whether "on" means the output is driven high or low
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+impl Default for Polarity {
+    fn default() -> Self {
+        Polarity::ActiveHigh
+    }
+}
+///How an output responds to the events it is triggered by
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    ///Turns on at each event and back off after `pulse_ms`
+    Pulse,
+    ///Turns on at each event and stays on until the port is stopped
+    LatchOnPlay,
+    ///Each event flips the output
+    Toggle,
+}
+impl Default for OutputMode {
+    fn default() -> Self {
+        OutputMode::Pulse
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct AdamCommand {
-    adam_module: AdamID,
-    digital_output_number: u8,
+    pub adam_module: AdamID,
+    pub digital_output_number: u8,
+    ///How long a pulse holds the output on for
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u64,
+    #[serde(default)]
+    pub polarity: Polarity,
+    #[serde(default)]
+    pub mode: OutputMode,
+    ///The port events that trigger the output. A latch is always released by stop
+    #[serde(default = "default_events")]
+    pub events: Vec<EventKind>,
+    ///Fires the command a while after play instead of at the play. Cancelled if the port is stopped first
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
 }
 
 impl AdamCommand {
@@ -28,172 +96,817 @@ impl AdamCommand {
         Self {
             adam_module,
             digital_output_number,
+            pulse_ms: default_pulse_ms(),
+            polarity: Polarity::default(),
+            mode: OutputMode::default(),
+            events: default_events(),
+            schedule: None,
         }
     }
+    ///The level to write to the output for it to be `on` or off
+    fn level(&self, on: bool) -> bool {
+        on == (self.polarity == Polarity::ActiveHigh)
+    }
 }
 
 type AdamID = u8;
 type VDCPPortNum = u8;
-type CommandMapping = HashMap<VDCPPortNum, AdamCommand>;
-type AdamIPs = HashMap<AdamID, Ipv4Addr>;
-type URL = String;
-type Key = String;
-type Value = String;
-type FormData = Vec<(Key, Value)>;
+///The adam commands to run for each port
+pub type CommandMapping = HashMap<VDCPPortNum, Vec<AdamCommand>>;
+///Whether each toggled output is currently on, by module and output number
+type ToggleStates = HashMap<(AdamID, u8), bool>;
+
+fn default_username() -> String {
+    "root".to_string()
+}
+fn default_health_poll_ms() -> u64 {
+    2000
+}
+fn default_tally_poll_ms() -> u64 {
+    200
+}
+fn default_timeout_ms() -> u64 {
+    150
+}
+fn default_retries() -> u32 {
+    2
+}
+fn default_backoff_ms() -> u64 {
+    5
+}
+fn default_queue_capacity() -> usize {
+    32
+}
+fn default_stale_after_ms() -> u64 {
+    1000
+}
+
+///Timeouts, retries and queueing for requests to a module.
+///Retries of a pulse are given up once the pulse would already be over.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RequestTiming {
+    #[serde(default = "default_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_timeout_ms")]
+    pub read_timeout_ms: u64,
+    ///How many times a failed request is sent again
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    ///Wait before the first retry, doubled for each one after
+    #[serde(default = "default_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    ///How many requests can wait for the module before new ones are dropped. Requests that switch outputs off can go past it
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    ///Requests whose event is older than this when their turn comes are dropped rather than sent late
+    #[serde(default = "default_stale_after_ms")]
+    pub stale_after_ms: u64,
+}
+impl Default for RequestTiming {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: default_timeout_ms(),
+            read_timeout_ms: default_timeout_ms(),
+            retries: default_retries(),
+            retry_backoff_ms: default_backoff_ms(),
+            queue_capacity: default_queue_capacity(),
+            stale_after_ms: default_stale_after_ms(),
+        }
+    }
+}
+impl RequestTiming {
+    fn backoff(&self, attempt: u32) -> Duration {
+        Duration::from_millis(self.retry_backoff_ms.saturating_mul(1 << (attempt - 1).min(16)))
+    }
+}
+
+///How the digital outputs of an adam module are set
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AdamProtocol {
+    ///The http REST api. Every request is a new http connection, which is slow on some units
+    Rest,
+    ///Writes the output coils directly over Modbus/TCP
+    Modbus(ModbusSettings),
+}
+impl Default for AdamProtocol {
+    fn default() -> Self {
+        AdamProtocol::Rest
+    }
+}
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Http,
+    Https,
+}
+impl Default for Scheme {
+    fn default() -> Self {
+        Scheme::Http
+    }
+}
+impl Scheme {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+///A password given directly in the config or a reference to where it is kept
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Plain(String),
+    ///Read from an environment variable
+    Env { env: String },
+    ///Read from the first line of a file
+    File { file: PathBuf },
+}
+impl Default for Secret {
+    //The factory default password of ADAM-6000 modules
+    fn default() -> Self {
+        Secret::Plain("admin".to_string())
+    }
+}
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Plain(_) => write!(f, "Plain(***)"),
+            Secret::Env { env } => write!(f, "Env({:})", env),
+            Secret::File { file } => write!(f, "File({:?})", file),
+        }
+    }
+}
+impl Secret {
+    pub fn resolve(&self) -> Result<String, String> {
+        match self {
+            Secret::Plain(password) => Ok(password.clone()),
+            Secret::Env { env } => std::env::var(env).map_err(|e| {
+                format!("could not read password from environment variable {:}: {:}", env, e)
+            }),
+            Secret::File { file } => std::fs::read_to_string(file)
+                .map(|contents| contents.lines().next().unwrap_or("").to_string())
+                .map_err(|e| format!("could not read password file {:?}: {:}", file, e)),
+        }
+    }
+    ///A copy that is safe to show to users
+    pub fn redacted(&self) -> Secret {
+        match self {
+            Secret::Plain(_) => Secret::Plain("***".to_string()),
+            other => other.clone(),
+        }
+    }
+}
+
+///An adam module as it is written in the config
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AdamModule {
+    pub host: String,
+    ///Defaults to the standard port for the protocol and scheme
+    #[serde(default)]
+    pub port: Option<u16>,
+    ///Only used by the REST api
+    #[serde(default)]
+    pub scheme: Scheme,
+    #[serde(default = "default_username")]
+    pub username: String,
+    #[serde(default)]
+    pub password: Secret,
+    #[serde(default)]
+    pub protocol: AdamProtocol,
+    #[serde(default)]
+    pub timing: RequestTiming,
+    ///How often the module is checked for being online. 0 turns checking off
+    #[serde(default = "default_health_poll_ms")]
+    pub health_poll_ms: u64,
+    ///How often the digital inputs wired to tallies are read
+    #[serde(default = "default_tally_poll_ms")]
+    pub tally_poll_ms: u64,
+    ///Overrides `adam_coalesce_ms` for this module
+    #[serde(default)]
+    pub coalesce_ms: Option<u64>,
+}
+impl Default for AdamModule {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: None,
+            scheme: Scheme::default(),
+            username: default_username(),
+            password: Secret::default(),
+            protocol: AdamProtocol::default(),
+            timing: RequestTiming::default(),
+            health_poll_ms: default_health_poll_ms(),
+            tally_poll_ms: default_tally_poll_ms(),
+            coalesce_ms: None,
+        }
+    }
+}
+impl AdamModule {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or_else(|| match (&self.protocol, &self.scheme) {
+            (AdamProtocol::Modbus(_), _) => 502,
+            (AdamProtocol::Rest, Scheme::Http) => 80,
+            (AdamProtocol::Rest, Scheme::Https) => 443,
+        })
+    }
+}
+pub type AdamModules = HashMap<AdamID, AdamModule>;
+
+#[derive(Clone)]
+struct Password(String);
+impl Debug for Password {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+///An adam module with its password loaded, ready to send requests to
+#[derive(Clone, Debug)]
+pub struct AdamUnit {
+    pub module: AdamModule,
+    password: Password,
+}
+impl AdamUnit {
+    pub fn load(module: &AdamModule) -> Result<AdamUnit, String> {
+        Ok(AdamUnit {
+            module: module.clone(),
+            password: Password(module.password.resolve()?),
+        })
+    }
+    ///The full url of a path on the module's REST api
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{:}://{:}:{:}{:}",
+            self.module.scheme.as_str(),
+            self.module.host,
+            self.module.port(),
+            path
+        )
+    }
+}
+pub type AdamUnits = HashMap<AdamID, AdamUnit>;
+
+///A set of outputs to change on a single adam module
 #[derive(Debug)]
-enum RequestType {
-    Pulse,
+struct AdamRequest {
+    module: AdamID,
+    unit: AdamUnit,
+    ///The vdcp ports the request was made for
+    ports: Vec<u8>,
+    ///When the earliest event in the request happened
+    received: SystemTime,
+    ///Output levels to set straight away
+    outputs: Vec<(u8, bool)>,
+    ///Levels to set once each pulse is over, shortest pulse first
+    releases: Vec<(Duration, Vec<(u8, bool)>)>,
+    ///Switches a latch off or flips a toggle. Dropping it would leave the output in the wrong state,
+    ///so it is sent however late it is, like a pulse release
+    must_send: bool,
 }
-///Logs errors if the config is in some way broken.
-fn check_for_config_errors(port_mapping: &CommandMapping, unit_ips: &AdamIPs) {
-    for (_, port) in port_mapping {
-        if !(unit_ips.contains_key(&port.adam_module)) {
-            error!(
-                "the adam module {:} doesn't have an ip listed in the unit ips given {:?}",
-                port.adam_module, unit_ips
-            );
+///Why setting outputs failed
+#[derive(Debug)]
+struct SendError {
+    ///The http status, if the module answered over http
+    status: Option<u16>,
+    message: String,
+}
+///Where the result of each request is reported
+#[derive(Clone)]
+struct Reporting {
+    log: Arc<TriggerLog>,
+    alarms: PortAlarmMap,
+}
+///The commands waiting for a module's coalescing window to close
+struct Pending {
+    due: Instant,
+    commands: Vec<(AdamCommand, PortEvent)>,
+}
+///What the adam output keeps track of between events
+#[derive(Default)]
+struct AdamState {
+    toggles: ToggleStates,
+    pending: HashMap<AdamID, Pending>,
+    scheduler: Scheduler,
+    ///The commands the state was built for
+    mapping: CommandMapping,
+}
+impl AdamState {
+    ///Takes the state over for a new mapping. Ports whose commands changed lose their waiting and scheduled commands,
+    ///and returns the requests that switch off latches and toggles that are no longer in the mapping
+    fn adopt(&mut self, mapping: CommandMapping, units: &AdamUnits) -> Vec<AdamRequest> {
+        let changed: HashSet<u8> = self
+            .mapping
+            .keys()
+            .chain(mapping.keys())
+            .filter(|port| self.mapping.get(*port) != mapping.get(*port))
+            .copied()
+            .collect();
+        for pending in self.pending.values_mut() {
+            pending.commands.retain(|(_, event)| !changed.contains(&event.logical_port));
         }
+        self.pending.retain(|_, pending| !pending.commands.is_empty());
+        self.scheduler.forget(&changed);
+
+        let outputs_in = |mapping: &CommandMapping, modes: &[OutputMode]| -> HashSet<(AdamID, u8)> {
+            mapping
+                .values()
+                .flatten()
+                .filter(|command| modes.contains(&command.mode))
+                .map(|command| (command.adam_module, command.digital_output_number))
+                .collect()
+        };
+        let held = outputs_in(&mapping, &[OutputMode::LatchOnPlay, OutputMode::Toggle]);
+        let gone: CommandMapping = self
+            .mapping
+            .iter()
+            .map(|(port, commands)| {
+                let commands = commands
+                    .iter()
+                    .filter(|command| !held.contains(&(command.adam_module, command.digital_output_number)))
+                    .cloned()
+                    .collect();
+                (*port, commands)
+            })
+            .collect();
+        let requests = switch_off(&gone, units, &self.toggles);
+        let toggled = outputs_in(&mapping, &[OutputMode::Toggle]);
+        self.toggles.retain(|output, _| toggled.contains(output));
+        self.mapping = mapping;
+        requests
+    }
+}
+///Hands the adam state over from each adam output to the one that replaces it when the config changes,
+///so toggles, scheduled commands and commands waiting to be sent carry on
+#[derive(Default)]
+pub struct AdamCarryOver {
+    ///Held by the running output, the one replacing it waits for it to finish
+    state: Mutex<AdamState>,
+    shutting_down: AtomicBool,
+}
+impl AdamCarryOver {
+    ///The running output switches off everything it latched or toggled on once its events stop, rather than handing over
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+}
+///What a command does to its output in response to an event
+#[derive(Debug)]
+struct OutputAction {
+    number: u8,
+    level: bool,
+    release_after: Option<Duration>,
+    ///Switches a latch off or flips a toggle
+    must_send: bool,
+}
+///Loads the password of every module. Modules whose password can't be loaded are left out.
+fn load_units(modules: &AdamModules) -> AdamUnits {
+    modules
+        .iter()
+        .filter_map(|(id, module)| match AdamUnit::load(module) {
+            Ok(unit) => Some((*id, unit)),
+            Err(e) => {
+                error!("adam module {:} won't be used: {:}", id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+///The adam modules as a trigger output
+pub struct AdamOutput {
+    ///The adam commands associated to each playout port
+    pub port_mapping: CommandMapping,
+    ///The address, credentials and protocol for each adam module that an adam command points to
+    pub modules: AdamModules,
+    ///Raised for a port when a request made for it fails
+    pub alarms: PortAlarmMap,
+    ///Records every request
+    pub log: Arc<TriggerLog>,
+    ///Kept up to date by polling each module in the background
+    pub health: Arc<AdamHealth>,
+    ///The digital input wired to the tally of each port, if it has one
+    pub tally_mapping: TallyMapping,
+    ///How long to wait after an event for others to send in the same request, unless the module sets its own
+    pub coalesce_ms: u64,
+    ///The queue depth and wait of each module
+    pub queues: Arc<DispatchStats>,
+    ///Shared with the outputs before and after this one
+    pub carry_over: Arc<AdamCarryOver>,
+}
+impl TriggerOutput for AdamOutput {
+    fn name(&self) -> String {
+        "adam".to_string()
+    }
+    //Ports with a tally need their plays too, to know when to expect the tally
+    fn ports(&self) -> HashSet<u8> {
+        self.port_mapping
+            .keys()
+            .chain(self.tally_mapping.keys())
+            .copied()
+            .collect()
+    }
+    //the alarms are only compared by port, each port keeps its alarms for as long as it is in the config
+    fn settings(&self) -> Option<Value> {
+        let mut alarms: Vec<_> = self.alarms.keys().collect();
+        alarms.sort_unstable();
+        Some(json!({
+            "port_mapping": self.port_mapping,
+            "modules": self.modules,
+            "tally_mapping": self.tally_mapping,
+            "coalesce_ms": self.coalesce_ms,
+            "alarms": alarms,
+        }))
+    }
+    fn run(self: Box<Self>, events: Receiver<PortEvent>) {
+        start(events, *self)
     }
 }
 
 ///Will wait for info to come in on the `play_commands` channel and trigger the appropriate port in response.
 ///
-///`play_commands` A channel that receives play commands as a u8 representing the port to trigger play on.
-///
-///`port_mapping` is the adam port associated to each playout port
-///
-///`unit_ips` is the ip for each adam module that an adam command points to
+///`play_commands` A channel that receives play and stop events for each port. Returns once it closes,
+///which is how a new config or shutting down stops the communicator.
 ///
-/// 
-pub fn start(
-    play_commands: Receiver<u8>,
-    port_mapping: CommandMapping,
-    unit_ips: AdamIPs,
-) -> Result<(), RecvError> {
+pub fn start(play_commands: Receiver<PortEvent>, output: AdamOutput) {
+    let AdamOutput {
+        port_mapping,
+        modules,
+        alarms,
+        log,
+        health,
+        tally_mapping,
+        coalesce_ms,
+        queues,
+        carry_over,
+    } = output;
+    //the previous output holds the state until it has finished
+    let mut state = carry_over.state.lock().unwrap_or_else(|e| e.into_inner());
     info!("Starting adam communicator");
-    check_for_config_errors(&port_mapping, &unit_ips);
+    let units = load_units(&modules);
+    //the background pollers stop when this returns
+    let running = Arc::new(());
+    health::start(&units, health, Arc::downgrade(&running));
+    let tallies = Arc::new(Tallies::new(tally_mapping, alarms.clone()));
+    tally::start(tallies.clone(), &units);
     info!("adam client setup, starting loop");
-    //continuous loop where incoming adam trigger requests sent by the vdcp apart of the program are handled/
-    //let mut time=std::time::Instant::now();
-    //let buffer=ArrayQueue::new(port_mapping.len()+1);
-    let thread_pool=rayon::ThreadPoolBuilder::new().num_threads(5).build().expect("Adam Thread pool failed to be created");
-
+    let mut dispatcher = Dispatcher::new(Reporting { log, alarms }, queues);
+    for request in state.adopt(port_mapping, &units) {
+        dispatcher.send(request);
+    }
+    let AdamState {
+        toggles,
+        pending,
+        scheduler,
+        mapping,
+    } = &mut *state;
 
     loop{
-         //We wait until we receive a command and then wait for any others that should be executed at the same time
-        let first=play_commands.recv()?;
-        thread::sleep(std::time::Duration::from_millis(11));
-        let mut rest:Vec<_>=play_commands.try_iter().collect();
-        rest.append(&mut vec![first]);
-
-        let adam_requests = make_commands(rest, &port_mapping, &unit_ips);
-       thread_pool.spawn( move ||{dispatch_adam_requests(adam_requests)})
-    }
-    
-}
-
-fn dispatch_adam_requests(commands: Vec<(RequestType, URL, FormData)>) {
-    commands.into_par_iter().for_each(|(req_type,address, body)|{
-        let  form: Vec<(&str, &str)> = body.iter().map(|(a, b)| (a.as_ref(), b.as_ref())).collect();
-        info!("{{Adam}} Sending Request {:} | {:?}",&address,&form);
-        send_req(&form,&address);
-        //If it was a pulse we wait a little while then switch the port back to its original state
-        match req_type{RequestType::Pulse =>{
-            thread::sleep(Duration::from_millis(20)); 
-            let off_form:Vec<(&str, &str)>= form.iter().map(|(a,b)|{
-                    match *b {
-                        "1"=> (*a,"0"),
-                        "0"=> (*a,"1"),
-                        _=>{
-                          error!("Could not run pulse. Sending data that cannot be reversed, simply resending message"); 
-                            (*a,*b)
-                        }
-                    }
-                }).collect();
-            send_req(&off_form, &address);},
+        //We wait for an event, or for the next module's window to close so its commands can be sent together
+        let next_due = pending.values().map(|p| p.due).chain(scheduler.next_due()).min();
+        let received = match next_due {
+            None => match play_commands.recv() {
+                Ok(event) => Some(event),
+                Err(RecvError) => break,
+            },
+            Some(due) => match play_commands.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+        };
+        let events: Vec<_> = received.into_iter().chain(play_commands.try_iter()).collect();
+        let now = Instant::now();
+        tallies.expect(&events, now);
+        scheduler.update(&events, mapping, now);
+        let scheduled = scheduler.take_due(now).into_iter().into_group_map();
+        for (module, commands) in group_commands(events, mapping).into_iter().chain(scheduled) {
+            let window = coalesce_window(module, &units, coalesce_ms);
+            pending
+                .entry(module)
+                .or_insert_with(|| Pending {
+                    due: now + window,
+                    commands: Vec::new(),
+                })
+                .commands
+                .extend(commands);
+        }
+
+        let due: Vec<_> = pending
+            .iter()
+            .filter(|(_, p)| p.due <= Instant::now())
+            .map(|(module, _)| *module)
+            .collect();
+        let groups = due
+            .into_iter()
+            .filter_map(|module| pending.remove(&module).map(|p| (module, p.commands)))
+            .collect();
+        for request in build_requests(groups, &units, toggles) {
+            dispatcher.send(request);
         }
     }
-    );
-}
-///Just a wrapper around ureq takes a http form and sends it.
-///see the `send_form` documentation in ureq for details
-fn send_req(form: &Vec<(&str, &str)>, address: &URL) {
-    //TODO: replace the username and password with something from a config file
-    let response = ureq::post(&address).auth("root", "admin").send_form(form);
-    match response.ok() {
-        false => error!(
-            "{{Adam}}Request {:} | {:?} to set digital ports on adam failed response: {:?}",
-            address, form, response
-        ),
-        true => info!(
-            "{{Adam}}Request {:} | {:?} to set digital ports on adam success. Response:{:?}",
-            address, form, response
+    //The events have stopped, either for a new config or for shutting down. Only shutting down switches off what we switched on
+    let waiting: usize = pending.values().map(|p| p.commands.len()).sum();
+    if carry_over.shutting_down.load(Ordering::SeqCst) {
+        if waiting > 0 {
+            warn!("{{Adam}}Dropping {:} commands that were waiting to be sent", waiting);
+        }
+        info!("{{Adam}}Shutting down, switching off latched outputs and finishing pulses");
+        for request in switch_off(mapping, &units, toggles) {
+            dispatcher.send(request);
+        }
+        *state = AdamState::default();
+    } else {
+        info!(
+            "{{Adam}}Stopping, handing {:} waiting commands over to the next adam output",
+            waiting
+        );
+    }
+    dispatcher.close();
+}
+///How long a module waits for more events after the first before sending
+fn coalesce_window(module: AdamID, units: &AdamUnits, default_ms: u64) -> Duration {
+    let ms = units
+        .get(&module)
+        .and_then(|unit| unit.module.coalesce_ms)
+        .unwrap_or(default_ms);
+    Duration::from_millis(ms)
+}
+
+///Records the outcome and raises or clears the alarm on each port it was for.
+///A failed release raises the alarm but only a successful trigger clears it.
+fn report(reporting: &Reporting, outcome: &TriggerOutcome) {
+    for port in &outcome.ports {
+        if let Some(alarms) = reporting.alarms.get(port) {
+            if outcome.failed() || !outcome.release {
+                alarms.output_failed.store(outcome.failed(), Ordering::Relaxed);
+            }
+        }
+    }
+    reporting.log.record(outcome.clone());
+}
+///Sets the outputs, retrying with backoff on failure until the retries run out or the deadline passes
+fn send_with_retries(
+    request: &AdamRequest,
+    outputs: &[(u8, bool)],
+    deadline: Option<Instant>,
+    release: bool,
+) -> TriggerOutcome {
+    let timing = &request.unit.module.timing;
+    let started = Instant::now();
+    let unix_time_ms = crate::events::unix_time_ms(SystemTime::now());
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let result = set_outputs(&request.unit, outputs);
+        if result.is_ok() || attempts > timing.retries {
+            break result;
+        }
+        let backoff = timing.backoff(attempts);
+        if deadline.map_or(false, |deadline| Instant::now() + backoff >= deadline) {
+            warn!(
+                "{{Adam}}Not retrying outputs {:?} on adam {:}, the pulse would already be over",
+                outputs, request.unit.module.host
+            );
+            break result;
+        }
+        warn!(
+            "{{Adam}}Attempt {:} at setting outputs on adam {:} failed, retrying in {:?}",
+            attempts, request.unit.module.host, backoff
+        );
+        thread::sleep(backoff);
+    };
+    let (status, error) = match result {
+        Ok(status) => (status, None),
+        Err(e) => (e.status, Some(e.message)),
+    };
+    TriggerOutcome {
+        module: request.module,
+        host: request.unit.module.host.clone(),
+        ports: request.ports.clone(),
+        outputs: outputs.to_vec(),
+        release,
+        unix_time_ms,
+        latency_ms: started.elapsed().as_millis() as u64,
+        frame_latency_ms: if release {
+            None
+        } else {
+            SystemTime::now()
+                .duration_since(request.received)
+                .ok()
+                .map(|latency| latency.as_millis() as u64)
+        },
+        attempts,
+        status,
+        error,
+    }
+}
+///Sets the outputs on a module using whichever protocol it is set up for.
+///Returns the http status if there was one
+fn set_outputs(unit: &AdamUnit, outputs: &[(u8, bool)]) -> Result<Option<u16>, SendError> {
+    let result = match &unit.module.protocol {
+        AdamProtocol::Rest => rest::set_outputs(unit, outputs).map(Some),
+        AdamProtocol::Modbus(settings) => modbus::write_coils(
+            &unit.module.host,
+            unit.module.port(),
+            &unit.module.timing,
+            settings,
+            outputs,
+        )
+        .map(|_| None)
+        .map_err(|message| SendError {
+            status: None,
+            message,
+        }),
+    };
+    match &result {
+        Err(e) => error!(
+            "{{Adam}}Setting outputs {:?} on adam {:} failed: {:}",
+            outputs, unit.module.host, e.message
         ),
+        Ok(_) => info!("{{Adam}}Set outputs {:?} on adam {:}", outputs, unit.module.host),
     }
+    result
 }
 
-///Takes a play channel id and returns the appropriate command to send to the assigned adam
-fn make_commands<'a>(
-    mut ports_to_play: Vec<u8>,
+///Takes the events from ports and returns the appropriate requests to send to the assigned adams
+fn make_commands(
+    events: Vec<PortEvent>,
+    mapping: &CommandMapping,
+    units: &AdamUnits,
+    toggles: &mut ToggleStates,
+) -> Vec<AdamRequest> {
+    build_requests(group_commands(events, mapping), units, toggles)
+}
+///Pairs each event with the commands mapped to its port, grouped by the module they are for
+fn group_commands(
+    mut events: Vec<PortEvent>,
     mapping: &CommandMapping,
-    unit_ips: &AdamIPs,
-) -> Vec<(RequestType, URL, FormData)> {
-    ports_to_play.sort_unstable();
+) -> HashMap<AdamID, Vec<(AdamCommand, PortEvent)>> {
+    //stable so a play and stop on the same port keep their order
+    events.sort_by_key(|event| event.logical_port);
 
-    let get_adam_command = |port| -> Option<_> {
-        let command = mapping.get(port);
-        match command {
-            None => {
-                error!(
-                    "Port {:?} did not have an associated adam command. Not sending a play request",
-                    port
-                );
-                return None;
-            }
-            Some(this_command) => {
+    //ports only used for their tally have no commands
+    let get_adam_commands = |event: &PortEvent| -> Vec<_> {
+        let commands = mapping.get(&event.logical_port).map(Vec::as_slice).unwrap_or(&[]);
+        commands
+            .iter()
+            //scheduled commands fire from the scheduler rather than at the play
+            .filter(|this_command| this_command.schedule.is_none() || event.kind != EventKind::Play)
+            .map(|this_command| {
                 info!(
-                    "{{Adam}}Creating play command for port {:} with adam:{:?} ",
-                    port, this_command
+                    "{{Adam}}Creating command for {:?} with adam:{:?} ",
+                    event, this_command
                 );
-                return Some((this_command.adam_module, this_command));
-            }
-        }
+                (this_command.adam_module, (this_command.clone(), event.clone()))
+            })
+            .collect()
     };
-    let groups = ports_to_play
+    events
         .iter()
-        .filter_map(get_adam_command)
-        .into_group_map();
-
-    let get_adam_ip = |(key, commands)| {
-        let ip = unit_ips.get(&key);
-        match ip {
+        .flat_map(get_adam_commands)
+        .into_group_map()
+}
+///Turns the commands for each module into a single request per module
+fn build_requests(
+    groups: HashMap<AdamID, Vec<(AdamCommand, PortEvent)>>,
+    units: &AdamUnits,
+    toggles: &mut ToggleStates,
+) -> Vec<AdamRequest> {
+    let get_adam_unit = |(key, commands)| {
+        let unit = units.get(&key);
+        match unit {
             None => {
                 error!(
-                    "Adam module {:} didn't have an ip address listed. Not sending play request",
+                    "Adam module {:} isn't configured or couldn't be loaded. Not sending play request",
                     key
                 );
                 None
             }
-            Some(x) => Some((x, commands)),
+            Some(x) => Some((key, x, commands)),
         }
     };
 
     let res: Vec<_> = groups
         .into_iter()
-        .filter_map(get_adam_ip)
-        .map(|(ip, value)| create_post(*ip, value))
+        .filter_map(get_adam_unit)
+        .filter_map(|(module, unit, value)| create_request(module, unit, value, toggles))
         .collect();
     res
 }
-///Creates a http request string for the adam ip and pins given
-///It combines all the commands together int a single request
-fn create_post<'a>(ip: Ipv4Addr, pins: Vec<&AdamCommand>) -> (RequestType, URL, FormData) {
-    let address: URL = format!("http://{0}/digitaloutput/all/value", ip);
-    let body: Vec<_> = pins
+///Works out what level a command's output should go to for an event, if it changes at all
+fn command_action(command: &AdamCommand, event: &PortEvent, toggles: &mut ToggleStates) -> Option<OutputAction> {
+    let (level, release_after) = match (event.kind, command.mode) {
+        (EventKind::Stop, OutputMode::LatchOnPlay) => (command.level(false), None),
+        (kind, _) if !command.events.contains(&kind) => return None,
+        (_, OutputMode::Pulse) => (
+            command.level(true),
+            Some(Duration::from_millis(command.pulse_ms)),
+        ),
+        (_, OutputMode::LatchOnPlay) => (command.level(true), None),
+        (_, OutputMode::Toggle) => {
+            let on = toggles
+                .entry((command.adam_module, command.digital_output_number))
+                .or_insert(false);
+            *on = !*on;
+            (command.level(*on), None)
+        }
+    };
+    Some(OutputAction {
+        number: command.digital_output_number,
+        level,
+        release_after,
+        must_send: (event.kind == EventKind::Stop && command.mode == OutputMode::LatchOnPlay)
+            || command.mode == OutputMode::Toggle,
+    })
+}
+///Requests that switch off every latched output and every toggle that is on
+fn switch_off(mapping: &CommandMapping, units: &AdamUnits, toggles: &ToggleStates) -> Vec<AdamRequest> {
+    let mut modules: BTreeMap<AdamID, (Vec<u8>, BTreeMap<u8, bool>)> = BTreeMap::new();
+    for (port, commands) in mapping {
+        for command in commands {
+            let on = match command.mode {
+                OutputMode::Pulse => false,
+                OutputMode::LatchOnPlay => true,
+                OutputMode::Toggle => toggles
+                    .get(&(command.adam_module, command.digital_output_number))
+                    .copied()
+                    .unwrap_or(false),
+            };
+            if on {
+                let (ports, outputs) = modules.entry(command.adam_module).or_default();
+                ports.push(*port);
+                outputs.insert(command.digital_output_number, command.level(false));
+            }
+        }
+    }
+    modules
+        .into_iter()
+        .filter_map(|(module, (mut ports, outputs))| {
+            ports.sort_unstable();
+            ports.dedup();
+            Some(AdamRequest {
+                module,
+                unit: units.get(&module)?.clone(),
+                ports,
+                received: SystemTime::now(),
+                outputs: outputs.into_iter().collect(),
+                releases: Vec::new(),
+                must_send: true,
+            })
+        })
+        .collect()
+}
+///Creates a request for the adam module and commands given
+///It combines all the commands together int a single request, with one release per pulse length
+fn create_request(
+    module: AdamID,
+    unit: &AdamUnit,
+    commands: Vec<(AdamCommand, PortEvent)>,
+    toggles: &mut ToggleStates,
+) -> Option<AdamRequest> {
+    let mut actions: Vec<_> = commands
         .iter()
-        .map(|a| (format!("DO{:}", a.digital_output_number), "1".to_string()))
+        .filter_map(|(command, event)| {
+            command_action(command, event, toggles).map(|action| (event.logical_port, event.time, action))
+        })
         .collect();
-    (RequestType::Pulse, address, body)
+    if actions.is_empty() {
+        return None;
+    }
+    let mut ports: Vec<_> = actions.iter().map(|(port, _, _)| *port).collect();
+    ports.sort_unstable();
+    ports.dedup();
+    //a later action on an output replaces an earlier one, so a play and stop in one window leave a latch off
+    actions.sort_by_key(|(_, time, _)| *time);
+    let mut merged: Vec<OutputAction> = Vec::new();
+    for (_, _, action) in actions {
+        merged.retain(|earlier| earlier.number != action.number);
+        merged.push(action);
+    }
+    let outputs = merged.iter().map(|a| (a.number, a.level)).collect();
+    let must_send = merged.iter().any(|a| a.must_send);
+    let received = commands
+        .iter()
+        .map(|(_, event)| event.time)
+        .min()
+        .unwrap_or_else(SystemTime::now);
+    let mut releases: BTreeMap<Duration, Vec<(u8, bool)>> = BTreeMap::new();
+    for action in &merged {
+        if let Some(after) = action.release_after {
+            releases.entry(after).or_default().push((action.number, !action.level));
+        }
+    }
+    Some(AdamRequest {
+        module,
+        unit: unit.clone(),
+        ports,
+        received,
+        outputs,
+        releases: releases.into_iter().collect(),
+        must_send,
+    })
 }
 
 //--------==================================================-----
@@ -202,72 +915,420 @@ fn create_post<'a>(ip: Ipv4Addr, pins: Vec<&AdamCommand>) -> (RequestType, URL,
 
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
+    use std::net::SocketAddr;
     use test_env_log::test;
 
+    use super::mock::MockAdam;
     use super::*;
+    use crate::vdcp::types::PortAlarms;
 
-    fn get_test_data(ip1: Ipv4Addr, ip2: Ipv4Addr) -> (CommandMapping, AdamIPs) {
+    fn module(host: &str, port: u16) -> AdamModule {
+        AdamModule {
+            host: host.to_string(),
+            port: Some(port),
+            ..Default::default()
+        }
+    }
+    fn get_test_data(module1: AdamModule, module2: AdamModule) -> (CommandMapping, AdamUnits) {
         let mut mapping = CommandMapping::new();
         let adam_out_1 = AdamCommand::new(0, 0);
         let adam_out_2 = AdamCommand::new(0, 1);
         let adam_out_3 = AdamCommand::new(0, 3);
         let adam_out_4 = AdamCommand::new(1, 0);
-        mapping.insert(0, adam_out_1);
-        mapping.insert(1, adam_out_2);
-        mapping.insert(2, adam_out_3);
-        mapping.insert(3, adam_out_4);
-        let mut ips = AdamIPs::new();
-        ips.insert(0, ip1);
-        ips.insert(1, ip2);
-        (mapping, ips)
-    }
-    fn get_commands() -> Vec<(RequestType, URL, FormData)> {
-        let (map, ips) = get_test_data(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
-        let res = make_commands(vec![0, 3], &map, &ips);
-        return res;
-    }
-    /*     #[test]
+        mapping.insert(0, vec![adam_out_1]);
+        mapping.insert(1, vec![adam_out_2]);
+        mapping.insert(2, vec![adam_out_3]);
+        mapping.insert(3, vec![adam_out_4]);
+        let mut modules = AdamModules::new();
+        modules.insert(0, module1);
+        modules.insert(1, module2);
+        (mapping, load_units(&modules))
+    }
+    fn play(port: u8) -> PortEvent {
+        PortEvent::new(EventKind::Play, port, "clip")
+    }
+    fn stop(port: u8) -> PortEvent {
+        PortEvent::new(EventKind::Stop, port, "clip")
+    }
+    ///Sends the requests and waits for them and their releases to finish
+    fn dispatch_adam_requests(requests: Vec<AdamRequest>, reporting: &Reporting) {
+        let mut dispatcher = Dispatcher::new(reporting.clone(), Arc::new(DispatchStats::default()));
+        for request in requests {
+            dispatcher.send(request);
+        }
+        dispatcher.close();
+    }
+    fn reporting() -> Reporting {
+        Reporting {
+            log: Arc::new(TriggerLog::default()),
+            alarms: PortAlarmMap::new(),
+        }
+    }
+    fn start_mock(name: &str) -> MockAdam {
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        MockAdam::start(name, local, "root", "admin").expect("failed starting mock adam")
+    }
+    #[test]
     fn make_commands_test() {
-        let mut res = get_commands();
-        let mut truth: Vec<(&str, Vec<(&str, &str)>)> = vec![
-            (
-                "http://10.0.0.1/digitaloutput/all/value",
-                vec![("DO0", "1")],
-            ),
-            (
-                "http://10.0.0.2/digitaloutput/all/value",
-                vec![("DO0", "1")],
-            ),
-        ];
-        let mut conv_truth: Vec<_> = truth
-            .drain(..)
-            .map(|(a, mut b)| {
-                (
-                    String::from(a),
-                    b.drain(..)
-                        .map(|(a, b)| (String::from(a), String::from(b)))
-                        .collect(),
-                )
+        let (map, units) = get_test_data(module("10.0.0.1", 80), module("10.0.0.2", 80));
+        let events = vec![play(0), play(3), play(2)];
+        let mut res: Vec<_> = make_commands(events, &map, &units, &mut ToggleStates::new())
+            .into_iter()
+            .map(|mut request| {
+                request.outputs.sort();
+                (request.unit.module.host, request.outputs)
             })
             .collect();
-
-        //we sort them both because order is not necessarily preserved
-        conv_truth.sort();
+        //we sort because order is not necessarily preserved
         res.sort();
-        assert_eq!(res, conv_truth);
-    } */
+        let truth = vec![
+            ("10.0.0.1".to_string(), vec![(0, true), (3, true)]),
+            ("10.0.0.2".to_string(), vec![(0, true)]),
+        ];
+        assert_eq!(res, truth);
+    }
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
     #[test]
     fn trigger_adam_test() {
         init();
-        info!("Checking whether it still works...");
-        let (mapping, ips) =
-            get_test_data(Ipv4Addr::new(10, 44, 8, 92), Ipv4Addr::new(10, 44, 8, 93));
-        let commands = make_commands(vec![0, 1], &mapping, &ips);
+        let (adam_0, adam_1) = (start_mock("0"), start_mock("1"));
+        let (mapping, units) = get_test_data(
+            module("127.0.0.1", adam_0.address.port()),
+            module("127.0.0.1", adam_1.address.port()),
+        );
+        let events = vec![play(0), play(1)];
+        let commands = make_commands(events, &mapping, &units, &mut ToggleStates::new());
         println!("Commands are {:?}", commands);
-        dispatch_adam_requests(commands);
+        dispatch_adam_requests(commands, &reporting());
+
+        //both outputs go high in one request and come back down together after the pulse
+        let history = adam_0.history();
+        assert_eq!(history.len(), 2);
+        let mut on = history[0].outputs.clone();
+        on.sort();
+        assert_eq!(on, vec![(0, true), (1, true)]);
+        let mut off = history[1].outputs.clone();
+        off.sort();
+        assert_eq!(off, vec![(0, false), (1, false)]);
+        assert!(history[1].at.duration_since(history[0].at) >= Duration::from_millis(20));
+        assert!(adam_0.outputs().values().all(|on| !on));
+        //nothing was mapped to the second module
+        assert!(adam_1.history().is_empty());
+    }
+    #[test]
+    fn coalescing_window_per_module() {
+        init();
+        let (adam_0, adam_1) = (start_mock("immediate"), start_mock("gathers"));
+        let immediate = AdamModule {
+            coalesce_ms: Some(0),
+            health_poll_ms: 0,
+            ..module("127.0.0.1", adam_0.address.port())
+        };
+        let gathers = AdamModule {
+            health_poll_ms: 0,
+            ..module("127.0.0.1", adam_1.address.port())
+        };
+        let (mut port_mapping, _) = get_test_data(immediate.clone(), gathers.clone());
+        port_mapping.insert(4, vec![AdamCommand::new(1, 1)]);
+        let mut modules = AdamModules::new();
+        modules.insert(0, immediate);
+        modules.insert(1, gathers);
+        let log = Arc::new(TriggerLog::default());
+        let output = AdamOutput {
+            port_mapping,
+            modules,
+            alarms: PortAlarmMap::new(),
+            log: log.clone(),
+            health: Arc::new(AdamHealth::default()),
+            tally_mapping: TallyMapping::new(),
+            coalesce_ms: 150,
+            queues: Arc::new(DispatchStats::default()),
+            carry_over: Arc::new(AdamCarryOver::default()),
+        };
+        let (sender, receiver) = channel();
+        thread::spawn(move || start(receiver, output));
+
+        //a controller that staggers a two port play
+        sender.send(play(0)).unwrap();
+        sender.send(play(3)).unwrap();
+        thread::sleep(Duration::from_millis(40));
+        sender.send(play(1)).unwrap();
+        sender.send(play(4)).unwrap();
+        thread::sleep(Duration::from_millis(400));
+
+        //without a window each play is its own request
+        let history = adam_0.history();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0].outputs, vec![(0, true)]);
+        assert_eq!(history[2].outputs, vec![(1, true)]);
+        //the 150ms window gathered both plays into one
+        let history = adam_1.history();
+        assert_eq!(history.len(), 2);
+        let mut on = history[0].outputs.clone();
+        on.sort();
+        assert_eq!(on, vec![(0, true), (1, true)]);
+
+        let latency = log.latency();
+        assert_eq!(latency.triggers, 3);
+        assert!(latency.max_ms.unwrap() >= 150);
+        assert!(latency.min_ms.unwrap() < 150);
+    }
+    #[test]
+    fn new_config_carries_toggles_over_and_only_shutdown_switches_off() {
+        init();
+        let (adam_0, adam_1) = (start_mock("latches"), start_mock("toggles"));
+        let quiet = |adam: &MockAdam| AdamModule {
+            coalesce_ms: Some(0),
+            health_poll_ms: 0,
+            ..module("127.0.0.1", adam.address.port())
+        };
+        let (mut port_mapping, _) = get_test_data(quiet(&adam_0), quiet(&adam_1));
+        port_mapping.get_mut(&1).unwrap()[0].mode = OutputMode::LatchOnPlay;
+        port_mapping.get_mut(&3).unwrap()[0].mode = OutputMode::Toggle;
+        let mut modules = AdamModules::new();
+        modules.insert(0, quiet(&adam_0));
+        modules.insert(1, quiet(&adam_1));
+        let carry_over = Arc::new(AdamCarryOver::default());
+        let run = |events: Vec<PortEvent>| {
+            let output = AdamOutput {
+                port_mapping: port_mapping.clone(),
+                modules: modules.clone(),
+                alarms: PortAlarmMap::new(),
+                log: Arc::new(TriggerLog::default()),
+                health: Arc::new(AdamHealth::default()),
+                tally_mapping: TallyMapping::new(),
+                coalesce_ms: 0,
+                queues: Arc::new(DispatchStats::default()),
+                carry_over: carry_over.clone(),
+            };
+            let (sender, receiver) = channel();
+            for event in events {
+                sender.send(event).unwrap();
+            }
+            drop(sender);
+            start(receiver, output);
+        };
+
+        run(vec![play(1), play(3)]);
+        assert_eq!(adam_0.history().len(), 1, "the latch is left on for the next output");
+        assert_eq!(adam_1.history().len(), 1);
+        //the next output knows the toggle is on
+        run(vec![play(3)]);
+        assert_eq!(adam_1.history()[1].outputs, vec![(0, false)]);
+
+        carry_over.shut_down();
+        run(vec![]);
+        let history = adam_0.history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].outputs, vec![(1, false)]);
+        assert_eq!(adam_1.history().len(), 2, "the toggle was already off");
+    }
+    #[test]
+    fn changed_ports_lose_their_state() {
+        let (mut map, units) = get_test_data(module("10.0.0.1", 80), module("10.0.0.2", 80));
+        map.get_mut(&1).unwrap()[0].mode = OutputMode::LatchOnPlay;
+        map.get_mut(&3).unwrap()[0].mode = OutputMode::Toggle;
+        let mut state = AdamState::default();
+        assert!(state.adopt(map.clone(), &units).is_empty());
+        state.toggles.insert((1, 0), true);
+        let commands = vec![(map[&0][0].clone(), play(0)), (map[&2][0].clone(), play(2))];
+        state.pending.insert(
+            0,
+            Pending {
+                due: Instant::now(),
+                commands,
+            },
+        );
+
+        let mut changed = map.clone();
+        changed.remove(&1);
+        changed.get_mut(&2).unwrap()[0].digital_output_number = 4;
+        let requests = state.adopt(changed, &units);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].ports, vec![1]);
+        assert_eq!(requests[0].outputs, vec![(1, false)]);
+        let ports: Vec<_> = state.pending[&0].commands.iter().map(|(_, event)| event.logical_port).collect();
+        assert_eq!(ports, vec![0]);
+        assert_eq!(state.toggles.get(&(1, 0)), Some(&true));
+    }
+    fn levels(requests: Vec<AdamRequest>) -> Vec<(u8, bool)> {
+        let mut outputs: Vec<_> = requests.into_iter().flat_map(|r| r.outputs).collect();
+        outputs.sort();
+        outputs
+    }
+    #[test]
+    fn port_with_several_outputs() {
+        let (mut map, units) = get_test_data(module("10.0.0.1", 80), module("10.0.0.2", 80));
+        map.get_mut(&0).unwrap().push(AdamCommand::new(0, 5));
+        map.get_mut(&0).unwrap().push(AdamCommand::new(1, 2));
+        let mut res: Vec<_> = make_commands(vec![play(0)], &map, &units, &mut ToggleStates::new())
+            .into_iter()
+            .map(|request| (request.module, request.ports, request.outputs))
+            .collect();
+        res.sort();
+        assert_eq!(
+            res,
+            vec![(0, vec![0], vec![(0, true), (5, true)]), (1, vec![0], vec![(2, true)])]
+        );
+    }
+    #[test]
+    fn stopping_switches_off_latches_and_toggles() {
+        let (mut map, units) = get_test_data(module("10.0.0.1", 80), module("10.0.0.2", 80));
+        map.get_mut(&1).unwrap()[0].mode = OutputMode::LatchOnPlay;
+        map.get_mut(&2).unwrap()[0].mode = OutputMode::Toggle;
+        map.get_mut(&2).unwrap()[0].polarity = Polarity::ActiveLow;
+        map.get_mut(&3).unwrap()[0].mode = OutputMode::Toggle;
+        let mut toggles = ToggleStates::new();
+        toggles.insert((0, 3), true);
+        toggles.insert((1, 0), false);
+        let requests = switch_off(&map, &units, &toggles);
+        assert_eq!(requests.len(), 1, "pulses and toggles that are off are left alone");
+        assert_eq!(requests[0].module, 0);
+        assert_eq!(requests[0].ports, vec![1, 2]);
+        assert_eq!(requests[0].outputs, vec![(1, false), (3, true)]);
+        assert!(requests[0].releases.is_empty());
+    }
+    #[test]
+    fn stop_only_affects_latches() {
+        let (mut map, units) = get_test_data(module("10.0.0.1", 80), module("10.0.0.2", 80));
+        map.get_mut(&1).unwrap()[0].mode = OutputMode::LatchOnPlay;
+        map.get_mut(&2).unwrap()[0].polarity = Polarity::ActiveLow;
+        map.get_mut(&2).unwrap()[0].mode = OutputMode::LatchOnPlay;
+        let mut toggles = ToggleStates::new();
+
+        let play = vec![play(0), play(1), play(2)];
+        let requests = make_commands(play, &map, &units, &mut toggles);
+        assert_eq!(requests.len(), 1);
+        //only the pulsed output gets released
+        assert_eq!(requests[0].releases, vec![(Duration::from_millis(20), vec![(0, false)])]);
+        assert_eq!(levels(requests), vec![(0, true), (1, true), (3, false)]);
+
+        let stop = vec![stop(0), stop(1), stop(2)];
+        let requests = make_commands(stop, &map, &units, &mut toggles);
+        assert!(requests[0].releases.is_empty());
+        assert_eq!(levels(requests), vec![(1, false), (3, true)]);
+    }
+    #[test]
+    fn play_and_stop_in_one_window_leave_a_latch_off() {
+        let (mut map, units) = get_test_data(module("10.0.0.1", 80), module("10.0.0.2", 80));
+        map.get_mut(&1).unwrap()[0].mode = OutputMode::LatchOnPlay;
+        map.get_mut(&0).unwrap()[0].mode = OutputMode::LatchOnPlay;
+        let events = vec![play(1), play(0), stop(1)];
+        let requests = make_commands(events, &map, &units, &mut ToggleStates::new());
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].ports, vec![0, 1]);
+        assert_eq!(requests[0].outputs, vec![(0, true), (1, false)]);
+        assert!(requests[0].releases.is_empty());
+    }
+    #[test]
+    fn toggles_flip_each_play() {
+        let (mut map, units) = get_test_data(module("10.0.0.1", 80), module("10.0.0.2", 80));
+        map.get_mut(&3).unwrap()[0].mode = OutputMode::Toggle;
+        let mut toggles = ToggleStates::new();
+        let mut play = || levels(make_commands(vec![play(3)], &map, &units, &mut toggles));
+        assert_eq!(play(), vec![(0, true)]);
+        assert_eq!(play(), vec![(0, false)]);
+        assert_eq!(play(), vec![(0, true)]);
+        assert!(make_commands(vec![stop(3)], &map, &units, &mut toggles).is_empty());
+    }
+    #[test]
+    fn outputs_follow_their_own_events() {
+        let (mut map, units) = get_test_data(module("10.0.0.1", 80), module("10.0.0.2", 80));
+        //port 0 gets a cue/preview gpi on output 4 and a cut back gpi on output 5
+        let mut preview = AdamCommand::new(0, 4);
+        preview.events = vec![EventKind::Cue];
+        let mut cut_back = AdamCommand::new(0, 5);
+        cut_back.events = vec![EventKind::Stop, EventKind::EndOfClip];
+        map.get_mut(&0).unwrap().extend(vec![preview, cut_back]);
+        let mut toggles = ToggleStates::new();
+        let mut levels_for = |event: PortEvent| levels(make_commands(vec![event], &map, &units, &mut toggles));
+
+        assert_eq!(levels_for(PortEvent::new(EventKind::Cue, 0, "clip")), vec![(4, true)]);
+        assert_eq!(levels_for(play(0)), vec![(0, true)]);
+        assert!(levels_for(PortEvent::new(EventKind::Still, 0, "clip")).is_empty());
+        assert_eq!(levels_for(PortEvent::new(EventKind::EndOfClip, 0, "clip")), vec![(5, true)]);
+        assert_eq!(levels_for(stop(0)), vec![(5, true)]);
+    }
+    #[test]
+    fn pulse_widths_are_released_in_order() {
+        init();
+        let adam = start_mock("widths");
+        let (mut map, units) = get_test_data(module("127.0.0.1", adam.address.port()), module("10.0.0.2", 80));
+        map.get_mut(&0).unwrap()[0].pulse_ms = 100;
+        map.get_mut(&1).unwrap()[0].pulse_ms = 40;
+        let events = vec![play(0), play(1)];
+        dispatch_adam_requests(make_commands(events, &map, &units, &mut ToggleStates::new()), &reporting());
+
+        let history = adam.history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].outputs, vec![(1, false)]);
+        assert_eq!(history[2].outputs, vec![(0, false)]);
+        assert!(history[1].at.duration_since(history[0].at) >= Duration::from_millis(40));
+        assert!(history[2].at.duration_since(history[0].at) >= Duration::from_millis(100));
+    }
+    #[test]
+    fn failed_requests_are_retried_and_raise_the_port_alarm() {
+        init();
+        //nothing listens on a port we have just closed so every connection is refused
+        let closed_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let adam = start_mock("recovers");
+        let (mut map, units) = get_test_data(module("127.0.0.1", closed_port), module("127.0.0.1", adam.address.port()));
+        map.get_mut(&0).unwrap()[0].mode = OutputMode::LatchOnPlay;
+        let reporting = reporting();
+        let mut reporting_alarms = PortAlarmMap::new();
+        reporting_alarms.insert(0, Arc::new(PortAlarms::default()));
+        reporting_alarms.insert(3, Arc::new(PortAlarms::default()));
+        let reporting = Reporting {
+            alarms: reporting_alarms,
+            ..reporting
+        };
+
+        let events = vec![play(0), play(3)];
+        dispatch_adam_requests(make_commands(events, &map, &units, &mut ToggleStates::new()), &reporting);
+
+        let outcomes = reporting.log.recent();
+        let failed: Vec<_> = outcomes.iter().filter(|o| o.failed()).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].ports, vec![0]);
+        assert_eq!(failed[0].attempts, RequestTiming::default().retries + 1);
+        assert!(reporting.alarms[&0].output_failed.load(Ordering::Relaxed));
+        assert!(!reporting.alarms[&3].output_failed.load(Ordering::Relaxed));
+        //the working module got both the pulse and its release
+        let ok: Vec<_> = outcomes.iter().filter(|o| !o.failed()).collect();
+        assert_eq!(ok.len(), 2);
+        assert!(ok.iter().all(|o| o.status == Some(200) && o.attempts == 1));
+    }
+    #[test]
+    fn module_urls() {
+        let mut adam = module("10.0.0.1", 8080);
+        let unit = AdamUnit::load(&adam).unwrap();
+        assert_eq!(unit.url("/digitaloutput/all/value"), "http://10.0.0.1:8080/digitaloutput/all/value");
+        adam.port = None;
+        adam.scheme = Scheme::Https;
+        assert_eq!(AdamUnit::load(&adam).unwrap().url("/x"), "https://10.0.0.1:443/x");
+        adam.protocol = AdamProtocol::Modbus(ModbusSettings::default());
+        assert_eq!(adam.port(), 502);
+    }
+    #[test]
+    fn secrets_from_env() {
+        std::env::set_var("VDCP_SPOOF_TEST_ADAM_PASSWORD", "hunter2");
+        let secret: Secret = serde_json::from_str(r#"{"env": "VDCP_SPOOF_TEST_ADAM_PASSWORD"}"#).unwrap();
+        assert_eq!(secret.resolve(), Ok("hunter2".to_string()));
+        let secret: Secret = serde_json::from_str(r#""plain""#).unwrap();
+        assert_eq!(secret.resolve(), Ok("plain".to_string()));
+        let secret: Secret = serde_json::from_str(r#"{"env": "VDCP_SPOOF_TEST_MISSING"}"#).unwrap();
+        assert!(secret.resolve().is_err());
+    }
+    #[test]
+    fn mock_rejects_bad_credentials() {
+        let adam = start_mock("auth");
+        let address = format!("http://{:}/digitaloutput/all/value", adam.host());
+        let response = ureq::post(&address)
+            .auth("root", "wrong")
+            .send_form(&[("DO0", "1")]);
+        assert_eq!(response.status(), 401);
+        assert!(adam.history().is_empty());
     }
 }
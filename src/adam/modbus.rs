@@ -0,0 +1,376 @@
+//===Adam Modbus/TCP backend===
+//ADAM-6000 modules expose their digital outputs as coils. Writing them directly avoids the
+//overhead of a http request per trigger.
+//Each thread keeps its connection to a module open between requests, so a trigger doesn't wait for a connect.
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+use super::RequestTiming;
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+
+static TRANSACTION_ID: AtomicU16 = AtomicU16::new(0);
+
+thread_local! {
+    ///The open connection to each module by host and port
+    static CONNECTIONS: RefCell<HashMap<(String, u16), TcpStream>> = RefCell::new(HashMap::new());
+}
+
+//On the ADAM-6050/6060 the digital outputs start at coil 00017
+fn default_coil_offset() -> u16 {
+    16
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ModbusSettings {
+    #[serde(default)]
+    pub unit_id: u8,
+    ///The coil address of digital output 0
+    #[serde(default = "default_coil_offset")]
+    pub coil_offset: u16,
+    ///The discrete input address of digital input 0
+    #[serde(default)]
+    pub input_offset: u16,
+}
+impl Default for ModbusSettings {
+    fn default() -> Self {
+        Self {
+            unit_id: 0,
+            coil_offset: default_coil_offset(),
+            input_offset: 0,
+        }
+    }
+}
+
+///Sets digital outputs by writing their coils.
+///Consecutive outputs are written together with function code 0F, lone outputs with 05.
+pub fn write_coils(
+    host: &str,
+    port: u16,
+    timing: &RequestTiming,
+    settings: &ModbusSettings,
+    outputs: &[(u8, bool)],
+) -> Result<(), String> {
+    let pdus = coil_runs(outputs)
+        .iter()
+        .map(|run| write_pdu(settings.coil_offset, run))
+        .collect::<Result<Vec<_>, _>>()?;
+    with_connection(host, port, timing, |stream| {
+        for pdu in &pdus {
+            transact(stream, settings.unit_id, pdu)?;
+        }
+        Ok(())
+    })
+    .map_err(|e| format!("writing coils {:?} failed: {:}", outputs, e))
+}
+
+///Reads `count` output coils starting at digital output `first`
+pub fn read_coils(
+    host: &str,
+    port: u16,
+    timing: &RequestTiming,
+    settings: &ModbusSettings,
+    first: u8,
+    count: u8,
+) -> Result<Vec<bool>, String> {
+    let pdu = read_pdu(READ_COILS, address(settings.coil_offset, first, count as usize)?, count as u16);
+    let response = with_connection(host, port, timing, |stream| transact(stream, settings.unit_id, &pdu))
+        .map_err(|e| format!("reading coils failed: {:}", e))?;
+    unpack_bits(&response, count as usize)
+}
+
+///Reads the first `count` digital inputs
+pub fn read_inputs(
+    host: &str,
+    port: u16,
+    timing: &RequestTiming,
+    settings: &ModbusSettings,
+    count: u8,
+) -> Result<Vec<bool>, String> {
+    let pdu = read_pdu(READ_DISCRETE_INPUTS, address(settings.input_offset, 0, count as usize)?, count as u16);
+    let response = with_connection(host, port, timing, |stream| transact(stream, settings.unit_id, &pdu))
+        .map_err(|e| format!("reading inputs failed: {:}", e))?;
+    unpack_bits(&response, count as usize)
+}
+
+///Runs `exchange` over this thread's open connection to the module, connecting first if there isn't one.
+///A connection that fails is closed, unless the module answered with an exception. One that had been left open is
+///tried once more on a new connection, as the module may have closed it while it was idle
+fn with_connection<T>(
+    host: &str,
+    port: u16,
+    timing: &RequestTiming,
+    exchange: impl Fn(&mut TcpStream) -> io::Result<T>,
+) -> io::Result<T> {
+    let key = (host.to_string(), port);
+    let open = CONNECTIONS.with(|connections| connections.borrow_mut().remove(&key));
+    let reused = open.is_some();
+    let mut stream = match open {
+        Some(stream) => stream,
+        None => connect(host, port, timing)?,
+    };
+    let result = match exchange(&mut stream) {
+        Err(e) if reused && e.kind() != io::ErrorKind::Other => {
+            stream = connect(host, port, timing)?;
+            exchange(&mut stream)
+        }
+        result => result,
+    };
+    match &result {
+        Err(e) if e.kind() != io::ErrorKind::Other => (),
+        _ => CONNECTIONS.with(|connections| {
+            connections.borrow_mut().insert(key, stream);
+        }),
+    }
+    result
+}
+///The address of the first of `count` coils or inputs from `first`, if the last of them is a modbus address
+fn address(offset: u16, first: u8, count: usize) -> Result<u16, String> {
+    offset
+        .checked_add(first as u16)
+        .filter(|start| start.checked_add((count as u16).saturating_sub(1)).is_some())
+        .ok_or_else(|| {
+            format!(
+                "offset {:} and {:} from {:} go past the last modbus address {:}",
+                offset,
+                count,
+                first,
+                u16::MAX
+            )
+        })
+}
+
+pub(super) fn connect(host: &str, port: u16, timing: &RequestTiming) -> io::Result<TcpStream> {
+    connect_to(host, port, timing)
+        .map_err(|e| io::Error::new(e.kind(), format!("could not connect to {:}:{:} : {:}", host, port, e)))
+}
+fn connect_to(host: &str, port: u16, timing: &RequestTiming) -> io::Result<TcpStream> {
+    let address = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host did not resolve to an address"))?;
+    let read_timeout = Duration::from_millis(timing.read_timeout_ms);
+    let stream = TcpStream::connect_timeout(&address, Duration::from_millis(timing.connect_timeout_ms))?;
+    stream.set_read_timeout(Some(read_timeout))?;
+    stream.set_write_timeout(Some(read_timeout))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+///Splits the outputs into runs of consecutive output numbers so each run can be a single write
+fn coil_runs(outputs: &[(u8, bool)]) -> Vec<Vec<(u8, bool)>> {
+    //reversed before the stable sort so the last level given for an output is the one kept
+    let mut sorted: Vec<_> = outputs.iter().rev().copied().collect();
+    sorted.sort_by_key(|(number, _)| *number);
+    sorted.dedup_by_key(|(number, _)| *number);
+    let mut runs: Vec<Vec<(u8, bool)>> = Vec::new();
+    for output in sorted {
+        match runs.last_mut() {
+            Some(run) if run.last().and_then(|(n, _)| n.checked_add(1)) == Some(output.0) => {
+                run.push(output)
+            }
+            _ => runs.push(vec![output]),
+        }
+    }
+    runs
+}
+
+///Builds the modbus pdu that writes one run of consecutive coils
+fn write_pdu(coil_offset: u16, run: &[(u8, bool)]) -> Result<Vec<u8>, String> {
+    let address = address(coil_offset, run[0].0, run.len())?.to_be_bytes();
+    Ok(if run.len() == 1 {
+        let value = if run[0].1 { 0xFF } else { 0x00 };
+        vec![WRITE_SINGLE_COIL, address[0], address[1], value, 0x00]
+    } else {
+        let quantity = (run.len() as u16).to_be_bytes();
+        let mut coils = vec![0u8; (run.len() + 7) / 8];
+        for (i, (_, value)) in run.iter().enumerate() {
+            if *value {
+                coils[i / 8] |= 1 << (i % 8);
+            }
+        }
+        let mut pdu = vec![
+            WRITE_MULTIPLE_COILS,
+            address[0],
+            address[1],
+            quantity[0],
+            quantity[1],
+            coils.len() as u8,
+        ];
+        pdu.append(&mut coils);
+        pdu
+    })
+}
+
+fn read_pdu(function: u8, address: u16, quantity: u16) -> Vec<u8> {
+    let address = address.to_be_bytes();
+    let quantity = quantity.to_be_bytes();
+    vec![function, address[0], address[1], quantity[0], quantity[1]]
+}
+///Unpacks the bits of a read response: function code, byte count then the bits lowest first
+fn unpack_bits(response: &[u8], count: usize) -> Result<Vec<bool>, String> {
+    let bytes = response.get(2..).unwrap_or(&[]);
+    if response.len() < 2 || response[1] as usize != bytes.len() || bytes.len() * 8 < count {
+        return Err(format!("read response {:x?} did not hold {:} values", response, count));
+    }
+    Ok((0..count).map(|i| bytes[i / 8] & (1 << (i % 8)) != 0).collect())
+}
+
+///Wraps a pdu in the Modbus/TCP header: transaction id, protocol id(always 0), length and unit id
+fn frame(transaction_id: u16, unit_id: u8, pdu: &[u8]) -> Vec<u8> {
+    let transaction = transaction_id.to_be_bytes();
+    let length = ((pdu.len() + 1) as u16).to_be_bytes();
+    let mut frame = vec![
+        transaction[0],
+        transaction[1],
+        0x00,
+        0x00,
+        length[0],
+        length[1],
+        unit_id,
+    ];
+    frame.extend_from_slice(pdu);
+    frame
+}
+
+///Sends a pdu and returns the pdu of the response, failing on exception responses
+pub(super) fn transact(stream: &mut TcpStream, unit_id: u8, pdu: &[u8]) -> io::Result<Vec<u8>> {
+    let transaction_id = TRANSACTION_ID.fetch_add(1, Ordering::Relaxed);
+    stream.write_all(&frame(transaction_id, unit_id, pdu))?;
+
+    let mut header = [0u8; 7];
+    stream.read_exact(&mut header)?;
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if length < 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "response was too short"));
+    }
+    let mut response = vec![0u8; length - 1];
+    stream.read_exact(&mut response)?;
+
+    if u16::from_be_bytes([header[0], header[1]]) != transaction_id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "response was for a different transaction",
+        ));
+    }
+    if response[0] == pdu[0] | 0x80 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("module returned exception code {:?}", response.get(1)),
+        ));
+    }
+    if response[0] != pdu[0] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("response had unexpected function code {:x?}", response[0]),
+        ));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn coil_runs_split_on_gaps() {
+        let runs = coil_runs(&[(3, true), (0, true), (1, false), (5, true)]);
+        assert_eq!(
+            runs,
+            vec![vec![(0, true), (1, false)], vec![(3, true)], vec![(5, true)]]
+        );
+        assert_eq!(coil_runs(&[(1, true), (2, true), (1, false)]), vec![vec![(1, false), (2, true)]]);
+    }
+    #[test]
+    fn single_coil_pdu() {
+        assert_eq!(write_pdu(16, &[(2, true)]), Ok(vec![0x05, 0x00, 18, 0xFF, 0x00]));
+        assert_eq!(write_pdu(16, &[(2, false)]), Ok(vec![0x05, 0x00, 18, 0x00, 0x00]));
+    }
+    #[test]
+    fn multiple_coil_pdu() {
+        let run: Vec<_> = (0..10).map(|i| (i, i % 2 == 0)).collect();
+        assert_eq!(
+            write_pdu(16, &run),
+            Ok(vec![0x0F, 0x00, 16, 0x00, 10, 2, 0b0101_0101, 0b0000_0001])
+        );
+    }
+    #[test]
+    fn addresses_past_the_last_coil_are_refused() {
+        assert!(write_pdu(u16::MAX, &[(0, true)]).is_ok());
+        assert!(write_pdu(u16::MAX, &[(1, true)]).is_err());
+        assert!(write_pdu(u16::MAX - 1, &[(0, true), (1, true), (2, true)]).is_err());
+        assert_eq!(address(u16::MAX - 9, 0, 10), Ok(u16::MAX - 9));
+        assert!(address(u16::MAX - 9, 0, 11).is_err());
+    }
+    #[test]
+    fn frame_has_mbap_header() {
+        assert_eq!(
+            frame(0x0102, 1, &[0x05, 0x00, 0x10, 0xFF, 0x00]),
+            vec![0x01, 0x02, 0x00, 0x00, 0x00, 0x06, 0x01, 0x05, 0x00, 0x10, 0xFF, 0x00]
+        );
+    }
+    #[test]
+    fn read_pdu_and_unpack() {
+        assert_eq!(read_pdu(READ_COILS, 16, 10), vec![0x01, 0x00, 16, 0x00, 10]);
+        assert_eq!(
+            unpack_bits(&[0x01, 2, 0b0000_0101, 0b0000_0010], 10),
+            Ok(vec![true, false, true, false, false, false, false, false, false, true])
+        );
+        assert!(unpack_bits(&[0x01, 1, 0xFF], 10).is_err());
+    }
+    ///Answers `count` requests the way a real module acknowledges writes and returns them
+    fn echo(stream: &mut TcpStream, count: usize) -> Vec<Vec<u8>> {
+        let mut requests = Vec::new();
+        for _ in 0..count {
+            let mut header = [0u8; 7];
+            stream.read_exact(&mut header).unwrap();
+            let mut pdu = vec![0u8; header[5] as usize - 1];
+            stream.read_exact(&mut pdu).unwrap();
+            let reply_pdu = pdu[..5].to_vec();
+            let mut reply = header.to_vec();
+            reply[5] = reply_pdu.len() as u8 + 1;
+            reply.extend_from_slice(&reply_pdu);
+            stream.write_all(&reply).unwrap();
+            requests.push(pdu);
+        }
+        requests
+    }
+    fn write(port: u16, outputs: &[(u8, bool)]) -> Result<(), String> {
+        write_coils("127.0.0.1", port, &RequestTiming::default(), &ModbusSettings::default(), outputs)
+    }
+    #[test]
+    fn write_coils_to_echo_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || echo(&mut listener.accept().unwrap().0, 2));
+        write(port, &[(0, true), (1, true), (4, false)]).unwrap();
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], vec![0x0F, 0x00, 16, 0x00, 2, 1, 0b11]);
+        assert_eq!(requests[1], vec![0x05, 0x00, 20, 0x00, 0x00]);
+    }
+    #[test]
+    fn connection_is_kept_open_and_reopened_once_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            //the module closes the first connection after two writes
+            let first = echo(&mut listener.accept().unwrap().0, 2);
+            let second = echo(&mut listener.accept().unwrap().0, 1);
+            (first.len(), second.len())
+        });
+        write(port, &[(0, true)]).unwrap();
+        write(port, &[(0, false)]).unwrap();
+        write(port, &[(1, true)]).unwrap();
+        assert_eq!(server.join().unwrap(), (2, 1));
+    }
+}
@@ -0,0 +1,116 @@
+//===Records of what happened to each adam request===
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use super::AdamID;
+
+///How many outcomes are kept for the web api
+const LOG_LENGTH: usize = 200;
+
+///The result of sending one request to a module, including any retries
+#[derive(Clone, Debug, Serialize)]
+pub struct TriggerOutcome {
+    pub module: AdamID,
+    pub host: String,
+    ///The vdcp ports whose events caused this request
+    pub ports: Vec<u8>,
+    pub outputs: Vec<(u8, bool)>,
+    ///True if this was switching a pulse back off rather than acting on the event
+    pub release: bool,
+    pub unix_time_ms: u64,
+    ///Time from starting the first attempt to finishing the last
+    pub latency_ms: u64,
+    ///Time from reading the vdcp frame that caused the trigger to the module confirming it.
+    ///Includes the coalescing window. Not set for releases
+    pub frame_latency_ms: Option<u64>,
+    pub attempts: u32,
+    ///The http status of the last attempt, if the module answered over http
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+impl TriggerOutcome {
+    pub fn failed(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+///How long successful triggers took from the vdcp frame to the module, over the recent outcomes
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub triggers: usize,
+    pub min_ms: Option<u64>,
+    pub mean_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+    pub max_ms: Option<u64>,
+}
+impl LatencySummary {
+    fn from_latencies(mut latencies: Vec<u64>) -> LatencySummary {
+        if latencies.is_empty() {
+            return LatencySummary::default();
+        }
+        latencies.sort();
+        let count = latencies.len();
+        LatencySummary {
+            triggers: count,
+            min_ms: latencies.first().copied(),
+            mean_ms: Some(latencies.iter().sum::<u64>() / count as u64),
+            p95_ms: latencies.get((count * 95 + 99) / 100 - 1).copied(),
+            max_ms: latencies.last().copied(),
+        }
+    }
+}
+
+///The most recent outcomes, shared with the web server
+#[derive(Default)]
+pub struct TriggerLog {
+    outcomes: Mutex<VecDeque<TriggerOutcome>>,
+}
+impl TriggerLog {
+    pub fn record(&self, outcome: TriggerOutcome) {
+        let mut outcomes = self.outcomes.lock().unwrap();
+        if outcomes.len() == LOG_LENGTH {
+            outcomes.pop_front();
+        }
+        outcomes.push_back(outcome);
+    }
+    ///Newest first
+    pub fn recent(&self) -> Vec<TriggerOutcome> {
+        self.outcomes.lock().unwrap().iter().rev().cloned().collect()
+    }
+    pub fn latency(&self) -> LatencySummary {
+        let outcomes = self.outcomes.lock().unwrap();
+        LatencySummary::from_latencies(
+            outcomes
+                .iter()
+                .filter(|outcome| !outcome.failed())
+                .filter_map(|outcome| outcome.frame_latency_ms)
+                .collect(),
+        )
+    }
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_summary() {
+        assert_eq!(LatencySummary::from_latencies(vec![]), LatencySummary::default());
+        let summary = LatencySummary::from_latencies((1..=20).rev().collect());
+        assert_eq!(
+            summary,
+            LatencySummary {
+                triggers: 20,
+                min_ms: Some(1),
+                mean_ms: Some(10),
+                p95_ms: Some(19),
+                max_ms: Some(20),
+            }
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//===Adam REST backend===
+#[cfg(not(test))]
+use log::info;
+
+#[cfg(test)]
+use std::println as info;
+
+use std::collections::BTreeMap;
+use ureq::{self, Agent};
+
+use super::{AdamUnit, SendError};
+
+///Sets digital outputs using the `/digitaloutput/all/value` endpoint.
+///All the outputs are combined into a single form post.
+///Returns the http status of the response
+pub fn set_outputs(unit: &AdamUnit, outputs: &[(u8, bool)]) -> Result<u16, SendError> {
+    let address = unit.url("/digitaloutput/all/value");
+    let body: Vec<_> = outputs
+        .iter()
+        .map(|(number, value)| (format!("DO{:}", number), if *value { "1" } else { "0" }))
+        .collect();
+    let form: Vec<(&str, &str)> = body.iter().map(|(a, b)| (a.as_ref(), *b)).collect();
+    info!("{{Adam}} Sending Request {:} | {:?}", &address, &form);
+    send_req(&form, &address, unit)
+}
+///Reads the outputs back from the module. Used to check the module is answering
+pub fn read_outputs(unit: &AdamUnit) -> Result<String, SendError> {
+    get(unit, &Agent::new(), &unit.url("/digitaloutput/all/value"))
+}
+///Reads the level of every digital input, by input number.
+///`agent` keeps the connection open between reads when the module allows it
+pub fn read_inputs(unit: &AdamUnit, agent: &Agent) -> Result<BTreeMap<u8, bool>, SendError> {
+    let body = get(unit, agent, &unit.url("/digitalinput/all/value"))?;
+    Ok(parse_values(&body, "DI"))
+}
+///Pulls the id and value out of each `<DI><ID>0</ID><VALUE>1</VALUE></DI>` style element
+fn parse_values(body: &str, element: &str) -> BTreeMap<u8, bool> {
+    let between = |text: &str, tag: &str| -> Option<String> {
+        let start = text.find(&format!("<{}>", tag))? + tag.len() + 2;
+        let end = text[start..].find(&format!("</{}>", tag))? + start;
+        Some(text[start..end].trim().to_string())
+    };
+    body.split(&format!("<{}>", element))
+        .skip(1)
+        .filter_map(|item| {
+            let id = between(item, "ID")?.parse().ok()?;
+            let value = between(item, "VALUE")?;
+            Some((id, value == "1"))
+        })
+        .collect()
+}
+fn get(unit: &AdamUnit, agent: &Agent, address: &str) -> Result<String, SendError> {
+    let timing = &unit.module.timing;
+    let response = agent
+        .get(address)
+        .timeout_connect(timing.connect_timeout_ms)
+        .timeout_read(timing.read_timeout_ms)
+        .auth(&unit.module.username, &unit.password.0)
+        .call();
+    if !response.ok() {
+        return Err(SendError {
+            status: if response.synthetic() { None } else { Some(response.status()) },
+            message: format!("Request {:} to adam failed response: {:?}", address, response),
+        });
+    }
+    let status = response.status();
+    response.into_string().map_err(|e| SendError {
+        status: Some(status),
+        message: format!("Failed reading response from {:}: {:}", address, e),
+    })
+}
+///Just a wrapper around ureq takes a http form and sends it.
+///see the `send_form` documentation in ureq for details
+fn send_req(form: &Vec<(&str, &str)>, address: &str, unit: &AdamUnit) -> Result<u16, SendError> {
+    let timing = &unit.module.timing;
+    let response = ureq::post(&address)
+        .timeout_connect(timing.connect_timeout_ms)
+        .timeout_read(timing.read_timeout_ms)
+        .auth(&unit.module.username, &unit.password.0)
+        .send_form(form);
+    match response.ok() {
+        false => Err(SendError {
+            //synthetic responses are ureq's way of reporting it never got an answer
+            status: if response.synthetic() { None } else { Some(response.status()) },
+            message: format!(
+                "Request {:} | {:?} to set digital ports on adam failed response: {:?}",
+                address, form, response
+            ),
+        }),
+        true => Ok(response.status()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_input_values() {
+        let body = "<?xml version=\"1.0\" ?><ADAM-6050 status=\"OK\"><DI><ID>0</ID><VALUE>0</VALUE></DI><DI><ID>1</ID><VALUE>1</VALUE></DI></ADAM-6050>";
+        let values = parse_values(body, "DI");
+        assert_eq!(values.into_iter().collect::<Vec<_>>(), vec![(0, false), (1, true)]);
+    }
+}
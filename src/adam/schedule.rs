@@ -0,0 +1,281 @@
+//===Delayed adam commands===
+//Commands with a schedule fire a set time after play, or before the clip is due to end, instead of at the play itself.
+//A still pauses them along with the clip, and the play that carries on from the still resumes them.
+#[cfg(not(test))]
+use log::{info, warn};
+
+#[cfg(test)]
+use std::{println as info, println as warn};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{AdamCommand, AdamID, CommandMapping};
+use crate::vdcp::types::{EventKind, PortEvent};
+
+///When a command fires relative to the play of its port
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Schedule {
+    AfterPlayMs(u64),
+    ///Relative to the end of the clip, worked out from its time on the web page
+    BeforeEndMs(u64),
+}
+impl Schedule {
+    ///How long after the play the command fires, if it can be worked out
+    fn delay(&self, play: &PortEvent) -> Option<Duration> {
+        match self {
+            Schedule::AfterPlayMs(ms) => Some(Duration::from_millis(*ms)),
+            Schedule::BeforeEndMs(ms) => play
+                .clip_remaining_ms
+                .map(|remaining| Duration::from_millis(remaining.saturating_sub(*ms))),
+        }
+    }
+}
+
+struct Waiting {
+    due: Instant,
+    ///How long was left when the port was held on a still. It doesn't fire until the port plays again
+    paused: Option<Duration>,
+    command: AdamCommand,
+    play: PortEvent,
+}
+
+///The scheduled commands waiting to fire. A stop on the port, or a play without one, cancels its commands
+#[derive(Default)]
+pub struct Scheduler {
+    waiting: Vec<Waiting>,
+    ///Ports that are playing
+    playing: HashSet<u8>,
+    ///Ports held on a still after playing, whose next play carries on rather than starting again
+    held: HashSet<u8>,
+}
+impl Scheduler {
+    ///Schedules the commands of each play, pauses them on a still and cancels the commands of each stopped port
+    pub fn update(&mut self, events: &[PortEvent], mapping: &CommandMapping, now: Instant) {
+        for event in events {
+            let port = event.logical_port;
+            match event.kind {
+                EventKind::Stop => {
+                    self.playing.remove(&port);
+                    self.held.remove(&port);
+                    self.cancel(port, "stopped");
+                }
+                EventKind::Still if self.playing.contains(&port) => {
+                    self.held.insert(port);
+                    self.pause(port, now);
+                }
+                EventKind::Play if self.held.remove(&port) => self.resume(port, now),
+                EventKind::Play => {
+                    //a play without a stop starts over rather than firing everything twice
+                    if !self.playing.insert(port) {
+                        self.cancel(port, "played again");
+                    }
+                    let commands = mapping.get(&port).map(Vec::as_slice).unwrap_or(&[]);
+                    //a command without play in its events would do nothing once due, validation warns about it
+                    let scheduled = commands.iter().filter(|command| command.events.contains(&EventKind::Play));
+                    for command in scheduled {
+                        if let Some(schedule) = command.schedule {
+                            self.schedule(schedule, command, event, now);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    fn pause(&mut self, port: u8, now: Instant) {
+        for waiting in self.waiting.iter_mut().filter(|waiting| waiting.play.logical_port == port) {
+            if waiting.paused.is_none() {
+                waiting.paused = Some(waiting.due.saturating_duration_since(now));
+            }
+        }
+    }
+    fn resume(&mut self, port: u8, now: Instant) {
+        for waiting in self.waiting.iter_mut().filter(|waiting| waiting.play.logical_port == port) {
+            if let Some(left) = waiting.paused.take() {
+                waiting.due = now + left;
+            }
+        }
+    }
+    fn schedule(&mut self, schedule: Schedule, command: &AdamCommand, play: &PortEvent, now: Instant) {
+        match schedule.delay(play) {
+            Some(delay) => {
+                info!(
+                    "{{Adam}}Output {:} of module {:} will fire in {:?} for port {:}",
+                    command.digital_output_number, command.adam_module, delay, play.logical_port
+                );
+                self.waiting.push(Waiting {
+                    due: now + delay,
+                    paused: None,
+                    command: command.clone(),
+                    play: play.clone(),
+                });
+            }
+            None => warn!(
+                "{{Adam}}Port {:} has no time for clip {:}, output {:} of module {:} won't fire before its end",
+                play.logical_port, play.clip, command.digital_output_number, command.adam_module
+            ),
+        }
+    }
+    fn cancel(&mut self, port: u8, why: &str) {
+        let before = self.waiting.len();
+        self.waiting.retain(|waiting| waiting.play.logical_port != port);
+        if self.waiting.len() != before {
+            info!(
+                "{{Adam}}Port {:} {:}, cancelled {:} scheduled outputs",
+                port,
+                why,
+                before - self.waiting.len()
+            );
+        }
+    }
+    ///Forgets the commands of ports whose commands have changed, they are scheduled again at their next play
+    pub fn forget(&mut self, ports: &HashSet<u8>) {
+        self.waiting.retain(|waiting| !ports.contains(&waiting.play.logical_port));
+    }
+    pub fn next_due(&self) -> Option<Instant> {
+        self.waiting
+            .iter()
+            .filter(|waiting| waiting.paused.is_none())
+            .map(|waiting| waiting.due)
+            .min()
+    }
+    ///Takes the commands that are due, each with the play that scheduled it.
+    ///The play is given the current time so latency is measured from when the command fired
+    pub fn take_due(&mut self, now: Instant) -> Vec<(AdamID, (AdamCommand, PortEvent))> {
+        let (due, waiting): (Vec<_>, Vec<_>) =
+            self.waiting.drain(..).partition(|waiting| waiting.paused.is_none() && waiting.due <= now);
+        self.waiting = waiting;
+        due.into_iter()
+            .map(|Waiting { command, mut play, .. }| {
+                play.time = SystemTime::now();
+                (command.adam_module, (command, play))
+            })
+            .collect()
+    }
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping() -> CommandMapping {
+        let mut after_play = AdamCommand::new(0, 1);
+        after_play.schedule = Some(Schedule::AfterPlayMs(500));
+        let mut before_end = AdamCommand::new(0, 2);
+        before_end.schedule = Some(Schedule::BeforeEndMs(2000));
+        let mut mapping = CommandMapping::new();
+        mapping.insert(1, vec![AdamCommand::new(0, 0), after_play, before_end]);
+        mapping
+    }
+    fn play(remaining_ms: Option<u64>) -> PortEvent {
+        let mut play = PortEvent::new(EventKind::Play, 1, "clip");
+        play.clip_remaining_ms = remaining_ms;
+        play
+    }
+    fn outputs(due: Vec<(AdamID, (AdamCommand, PortEvent))>) -> Vec<u8> {
+        due.into_iter()
+            .map(|(_, (command, _))| command.digital_output_number)
+            .collect()
+    }
+
+    #[test]
+    fn fires_after_play_and_before_end() {
+        let mapping = mapping();
+        let mut scheduler = Scheduler::default();
+        let now = Instant::now();
+        scheduler.update(&[play(Some(10_000))], &mapping, now);
+        assert_eq!(scheduler.next_due(), Some(now + Duration::from_millis(500)));
+        assert!(scheduler.take_due(now).is_empty());
+        assert_eq!(outputs(scheduler.take_due(now + Duration::from_millis(500))), vec![1]);
+        assert_eq!(outputs(scheduler.take_due(now + Duration::from_millis(8000))), vec![2]);
+        assert_eq!(scheduler.next_due(), None);
+    }
+
+    #[test]
+    fn stop_cancels_and_unknown_clip_end_is_skipped() {
+        let mapping = mapping();
+        let mut scheduler = Scheduler::default();
+        let now = Instant::now();
+        scheduler.update(&[play(None)], &mapping, now);
+        //only the after play command could be scheduled
+        assert_eq!(scheduler.waiting.len(), 1);
+        scheduler.update(&[PortEvent::new(EventKind::Stop, 1, "clip")], &mapping, now);
+        assert!(scheduler.take_due(now + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn still_pauses_until_play_carries_on() {
+        let mapping = mapping();
+        let mut scheduler = Scheduler::default();
+        let now = Instant::now();
+        let still = PortEvent::new(EventKind::Still, 1, "clip");
+        scheduler.update(&[play(Some(10_000))], &mapping, now);
+        scheduler.update(&[still.clone()], &mapping, now + Duration::from_millis(200));
+        assert_eq!(scheduler.next_due(), None);
+        assert!(scheduler.take_due(now + Duration::from_secs(60)).is_empty());
+
+        //carrying on from the still doesn't schedule the commands again
+        let resumed = now + Duration::from_secs(5);
+        scheduler.update(&[play(Some(9_800))], &mapping, resumed);
+        assert_eq!(scheduler.waiting.len(), 2);
+        assert_eq!(scheduler.next_due(), Some(resumed + Duration::from_millis(300)));
+        assert_eq!(outputs(scheduler.take_due(resumed + Duration::from_millis(300))), vec![1]);
+        assert!(scheduler.take_due(resumed + Duration::from_millis(7_700)).is_empty());
+        assert_eq!(outputs(scheduler.take_due(resumed + Duration::from_millis(7_800))), vec![2]);
+
+        //once everything has fired a still and play still don't fire them again
+        scheduler.update(&[still], &mapping, resumed + Duration::from_secs(9));
+        scheduler.update(&[play(Some(500))], &mapping, resumed + Duration::from_secs(10));
+        assert_eq!(scheduler.next_due(), None);
+    }
+
+    #[test]
+    fn still_before_play_doesnt_hold_anything() {
+        let mapping = mapping();
+        let mut scheduler = Scheduler::default();
+        let now = Instant::now();
+        scheduler.update(&[PortEvent::new(EventKind::Still, 1, "clip"), play(Some(10_000))], &mapping, now);
+        assert_eq!(scheduler.waiting.len(), 2);
+    }
+
+    #[test]
+    fn short_clips_fire_straight_away() {
+        let mapping = mapping();
+        let mut scheduler = Scheduler::default();
+        let now = Instant::now();
+        scheduler.update(&[play(Some(1500))], &mapping, now);
+        assert_eq!(outputs(scheduler.take_due(now)), vec![2]);
+    }
+
+    #[test]
+    fn playing_again_without_a_stop_starts_over() {
+        let mapping = mapping();
+        let mut scheduler = Scheduler::default();
+        let now = Instant::now();
+        scheduler.update(&[play(Some(10_000))], &mapping, now);
+        let again = now + Duration::from_millis(300);
+        scheduler.update(&[play(Some(10_000))], &mapping, again);
+        assert_eq!(scheduler.waiting.len(), 2);
+        assert!(scheduler.take_due(now + Duration::from_millis(500)).is_empty());
+        assert_eq!(outputs(scheduler.take_due(again + Duration::from_millis(500))), vec![1]);
+        assert_eq!(outputs(scheduler.take_due(again + Duration::from_millis(8000))), vec![2]);
+    }
+
+    #[test]
+    fn commands_that_dont_fire_on_play_arent_scheduled() {
+        let mut mapping = mapping();
+        for command in mapping.get_mut(&1).unwrap() {
+            command.events = vec![EventKind::Stop];
+        }
+        let mut scheduler = Scheduler::default();
+        scheduler.update(&[play(Some(10_000))], &mapping, Instant::now());
+        assert_eq!(scheduler.next_due(), None);
+    }
+}
@@ -0,0 +1,242 @@
+//===Adam digital input tally feedback===
+//The mixer's on-air tally is wired to digital inputs on the adam modules. Reading them back lets a port
+//report what is really on air and raise an error when a trigger doesn't make it to air.
+use itertools::Itertools;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{atomic::Ordering, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use ureq::Agent;
+
+use super::{modbus, rest, AdamID, AdamProtocol, AdamUnit, AdamUnits, Polarity};
+use crate::vdcp::types::{EventKind, PortAlarmMap, PortEvent};
+
+///A digital input wired to the tally of a port
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TallyInput {
+    pub adam_module: AdamID,
+    pub digital_input_number: u8,
+    #[serde(default)]
+    pub polarity: Polarity,
+    ///Report the port as playing or idle from the tally rather than from the play and stop commands
+    #[serde(default)]
+    pub follow_status: bool,
+    ///Raise a port error if the tally doesn't come on within this long of a play
+    #[serde(default)]
+    pub expect_within_ms: Option<u64>,
+}
+///The tally input of each port by port number
+pub type TallyMapping = HashMap<u8, TallyInput>;
+
+///Tracks the tally of every port and the plays still waiting for their tally
+pub struct Tallies {
+    mapping: TallyMapping,
+    alarms: PortAlarmMap,
+    ///When each port waiting for its tally gives up
+    expected: Mutex<HashMap<u8, Instant>>,
+}
+impl Tallies {
+    pub fn new(mapping: TallyMapping, alarms: PortAlarmMap) -> Tallies {
+        Tallies {
+            mapping,
+            alarms,
+            expected: Mutex::new(HashMap::new()),
+        }
+    }
+    ///Starts waiting for the tally of each played port that expects one. Stopping a port stops the wait.
+    pub fn expect(&self, events: &[PortEvent], now: Instant) {
+        let mut expected = self.expected.lock().unwrap();
+        for event in events {
+            match (event.kind, self.mapping.get(&event.logical_port)) {
+                (EventKind::Play, Some(TallyInput { expect_within_ms: Some(ms), .. })) => {
+                    expected.insert(event.logical_port, now + Duration::from_millis(*ms));
+                }
+                (EventKind::Stop, _) => {
+                    expected.remove(&event.logical_port);
+                }
+                _ => (),
+            }
+        }
+    }
+    ///Updates a port with its tally. `None` means the input couldn't be read
+    fn update(&self, port: u8, on: Option<bool>, now: Instant) {
+        let (input, alarms) = match (self.mapping.get(&port), self.alarms.get(&port)) {
+            (Some(input), Some(alarms)) => (input, alarms),
+            _ => return,
+        };
+        if input.follow_status {
+            alarms.set_tally(on);
+        }
+        let mut expected = self.expected.lock().unwrap();
+        if on == Some(true) {
+            expected.remove(&port);
+            if alarms.tally_missing.swap(false, Ordering::Relaxed) {
+                info!("{{Adam}}Tally for port {:} came on, clearing the error", port);
+            }
+        } else if expected.get(&port).map_or(false, |deadline| now >= *deadline) {
+            expected.remove(&port);
+            alarms.tally_missing.store(true, Ordering::Relaxed);
+            warn!(
+                "{{Adam}}Tally for port {:} did not come on within {:?}ms of playing",
+                port, input.expect_within_ms
+            );
+        }
+    }
+}
+
+///Starts a thread per module with tally inputs that reads them all every `tally_poll_ms` of the module.
+///The threads stop once the tallies are dropped.
+pub fn start(tallies: Arc<Tallies>, units: &AdamUnits) {
+    let by_module = tallies
+        .mapping
+        .iter()
+        .map(|(port, input)| (input.adam_module, (*port, input.clone())))
+        .into_group_map();
+    for (module, inputs) in by_module {
+        let unit = match units.get(&module) {
+            Some(unit) => unit.clone(),
+            None => {
+                warn!("{{Adam}}Tally inputs use adam module {:} which isn't configured", module);
+                continue;
+            }
+        };
+        let count = inputs.iter().map(|(_, i)| i.digital_input_number).max().unwrap_or(0) + 1;
+        let tallies = Arc::downgrade(&tallies);
+        let interval = Duration::from_millis(unit.module.tally_poll_ms);
+        thread::spawn(move || {
+            let agent = Agent::new();
+            let mut failing = false;
+            loop {
+                let values = match read_inputs(&unit, &agent, count) {
+                    Ok(values) => {
+                        if failing {
+                            info!("{{Adam}}Reading tally inputs from module {:} recovered", module);
+                        }
+                        failing = false;
+                        Some(values)
+                    }
+                    Err(e) => {
+                        if !failing {
+                            warn!("{{Adam}}Reading tally inputs from module {:} failed: {:}", module, e);
+                        }
+                        failing = true;
+                        None
+                    }
+                };
+                let now = Instant::now();
+                match tallies.upgrade() {
+                    Some(tallies) => {
+                        for (port, input) in &inputs {
+                            let on = values
+                                .as_ref()
+                                .and_then(|values| values.get(input.digital_input_number as usize))
+                                .map(|level| *level == (input.polarity == Polarity::ActiveHigh));
+                            tallies.update(*port, on, now);
+                        }
+                    }
+                    None => break,
+                }
+                thread::sleep(interval);
+            }
+        });
+    }
+}
+
+///Reads the levels of the first `count` digital inputs
+fn read_inputs(unit: &AdamUnit, agent: &Agent, count: u8) -> Result<Vec<bool>, String> {
+    match &unit.module.protocol {
+        AdamProtocol::Rest => {
+            let values = rest::read_inputs(unit, agent).map_err(|e| e.message)?;
+            (0..count)
+                .map(|i| {
+                    values
+                        .get(&i)
+                        .copied()
+                        .ok_or_else(|| format!("module did not report input {:}", i))
+                })
+                .collect()
+        }
+        AdamProtocol::Modbus(settings) => modbus::read_inputs(
+            &unit.module.host,
+            unit.module.port(),
+            &unit.module.timing,
+            settings,
+            count,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mock::MockAdam;
+    use super::super::AdamModule;
+    use super::*;
+    use crate::vdcp::types::PortAlarms;
+
+    fn tallies(follow_status: bool) -> (Tallies, Arc<PortAlarms>) {
+        let mut mapping = TallyMapping::new();
+        mapping.insert(
+            1,
+            TallyInput {
+                adam_module: 0,
+                digital_input_number: 2,
+                polarity: Polarity::ActiveHigh,
+                follow_status,
+                expect_within_ms: Some(100),
+            },
+        );
+        let alarms = Arc::new(PortAlarms::default());
+        let mut alarm_map = PortAlarmMap::new();
+        alarm_map.insert(1, alarms.clone());
+        (Tallies::new(mapping, alarm_map), alarms)
+    }
+
+    #[test]
+    fn missing_tally_raises_error_until_it_comes_on() {
+        let (tallies, alarms) = tallies(false);
+        let start = Instant::now();
+        tallies.expect(&[PortEvent::new(EventKind::Play, 1, "clip")], start);
+        tallies.update(1, Some(false), start + Duration::from_millis(50));
+        assert!(!alarms.any());
+        tallies.update(1, Some(false), start + Duration::from_millis(100));
+        assert!(alarms.tally_missing.load(Ordering::Relaxed));
+        tallies.update(1, Some(true), start + Duration::from_millis(150));
+        assert!(!alarms.any());
+        assert_eq!(alarms.tally(), None, "status only follows the tally when asked to");
+    }
+    #[test]
+    fn stop_cancels_expected_tally() {
+        let (tallies, alarms) = tallies(true);
+        let start = Instant::now();
+        tallies.expect(&[PortEvent::new(EventKind::Play, 1, "clip"), PortEvent::new(EventKind::Stop, 1, "clip")], start);
+        tallies.update(1, Some(false), start + Duration::from_millis(200));
+        assert!(!alarms.any());
+        assert_eq!(alarms.tally(), Some(false));
+    }
+    #[test]
+    fn unreadable_inputs_still_time_out() {
+        let (tallies, alarms) = tallies(true);
+        let start = Instant::now();
+        tallies.expect(&[PortEvent::new(EventKind::Play, 1, "clip")], start);
+        tallies.update(1, None, start + Duration::from_millis(200));
+        assert!(alarms.tally_missing.load(Ordering::Relaxed));
+        assert_eq!(alarms.tally(), None);
+    }
+    #[test]
+    fn read_inputs_from_mock() {
+        let mock = MockAdam::start("tally", "127.0.0.1:0".parse().unwrap(), "root", "admin").unwrap();
+        mock.set_input(2, true);
+        let unit = AdamUnit::load(&AdamModule {
+            host: "127.0.0.1".to_string(),
+            port: Some(mock.address.port()),
+            ..Default::default()
+        })
+        .unwrap();
+        let agent = Agent::new();
+        assert_eq!(read_inputs(&unit, &agent, 3), Ok(vec![false, false, true]));
+        mock.set_input(0, true);
+        assert_eq!(read_inputs(&unit, &agent, 3), Ok(vec![true, false, true]));
+    }
+}
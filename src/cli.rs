@@ -0,0 +1,175 @@
+//===Command line===
+//Where the config, web page and logs are and where the web server listens.
+//Each option can also be set with an environment variable, the command line wins when both are set.
+use std::path::PathBuf;
+
+use crate::logging::{self, LogFormat};
+
+pub const USAGE: &str = "Usage: vdcp-spoof [options]
+
+Options:
+  --config <path>         config file (VDCP_CONFIG, default ./config.yaml)
+  --address <address>     address the web server listens on (VDCP_ADDRESS, default 0.0.0.0)
+  --port <port>           port the web server listens on (VDCP_PORT, default 8000)
+  --static-dir <dir>      where the web page's files are (VDCP_STATIC_DIR, default ./public)
+  --log-dir <dir>         info log files (VDCP_LOG_DIR, default ./Logs)
+  --debug-log-dir <dir>   debug log files (VDCP_DEBUG_LOG_DIR, default ./Logs2)
+  --log-level <spec>      log levels like info,serial=debug, added to the config's (VDCP_LOG_LEVEL)
+  --log-format <format>   text or json (VDCP_LOG_FORMAT), overrides the config's
+  --public-url <url>      url the web page uses to reach the api (VDCP_PUBLIC_URL),
+                          defaults to the address the page was loaded from
+  --simulate-adam         send adam requests to local mock modules instead of the hardware
+  --check-config          check the config file and exit
+  -h, --help              show this";
+
+///The options that take a value, with the environment variable that can set each
+const VALUE_OPTIONS: [(&str, &str); 9] = [
+    ("config", "VDCP_CONFIG"),
+    ("address", "VDCP_ADDRESS"),
+    ("port", "VDCP_PORT"),
+    ("static-dir", "VDCP_STATIC_DIR"),
+    ("log-dir", "VDCP_LOG_DIR"),
+    ("debug-log-dir", "VDCP_DEBUG_LOG_DIR"),
+    ("log-level", "VDCP_LOG_LEVEL"),
+    ("log-format", "VDCP_LOG_FORMAT"),
+    ("public-url", "VDCP_PUBLIC_URL"),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub config_path: PathBuf,
+    pub address: String,
+    pub port: u16,
+    pub static_dir: PathBuf,
+    pub log_dir: PathBuf,
+    pub debug_log_dir: PathBuf,
+    ///Log levels applied over the ones in the config
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    ///Given to the web page as the api's url. Without it the page uses the address it was loaded from
+    pub public_url: Option<String>,
+    pub simulate_adam: bool,
+    pub check_config: bool,
+    pub help: bool,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            config_path: PathBuf::from("./config.yaml"),
+            address: "0.0.0.0".to_string(),
+            port: 8000,
+            static_dir: PathBuf::from("./public"),
+            log_dir: PathBuf::from("./Logs/"),
+            debug_log_dir: PathBuf::from("./Logs2/"),
+            log_level: None,
+            log_format: None,
+            public_url: None,
+            simulate_adam: false,
+            check_config: false,
+            help: false,
+        }
+    }
+}
+impl Options {
+    ///Reads the options from the command line and environment. Exits after printing the usage if they are wrong or `--help` is given
+    pub fn from_env() -> Options {
+        match Options::parse(std::env::args().skip(1), |name| std::env::var(name).ok()) {
+            Ok(options) if options.help => {
+                println!("{:}", USAGE);
+                std::process::exit(0);
+            }
+            Ok(options) => options,
+            Err(e) => {
+                eprintln!("{:}\n\n{:}", e, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+    fn parse(args: impl IntoIterator<Item = String>, env: impl Fn(&str) -> Option<String>) -> Result<Options, String> {
+        let mut options = Options::default();
+        for (name, var) in VALUE_OPTIONS.iter() {
+            if let Some(value) = env(var).filter(|value| !value.is_empty()) {
+                options.set(name, value).map_err(|e| format!("{:} in {:}", e, var))?;
+            }
+        }
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--simulate-adam" => options.simulate_adam = true,
+                "--check-config" => options.check_config = true,
+                "-h" | "--help" => options.help = true,
+                _ => {
+                    let option = arg.strip_prefix("--").ok_or_else(|| format!("unexpected argument {:}", arg))?;
+                    let (name, value) = match option.find('=') {
+                        Some(split) => (&option[..split], option[split + 1..].to_string()),
+                        None => (option, args.next().ok_or_else(|| format!("{:} needs a value", arg))?),
+                    };
+                    options.set(name, value)?;
+                }
+            }
+        }
+        Ok(options)
+    }
+    fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        match name {
+            "config" => self.config_path = PathBuf::from(value),
+            "address" => self.address = value,
+            "port" => self.port = value.parse().map_err(|_| format!("{:} isn't a valid port", value))?,
+            "static-dir" => self.static_dir = PathBuf::from(value),
+            "log-dir" => self.log_dir = PathBuf::from(value),
+            "debug-log-dir" => self.debug_log_dir = PathBuf::from(value),
+            "log-level" => {
+                logging::parse_spec(&value)?;
+                self.log_level = Some(value)
+            }
+            "log-format" => self.log_format = Some(LogFormat::parse(&value)?),
+            "public-url" => self.public_url = Some(value.trim_end_matches('/').to_string()),
+            _ => return Err(format!("unknown option --{:}", name)),
+        }
+        Ok(())
+    }
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str], env: &[(&str, &str)]) -> Result<Options, String> {
+        let env: Vec<(String, String)> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Options::parse(args.iter().map(|arg| arg.to_string()), |name| {
+            env.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+        })
+    }
+
+    #[test]
+    fn command_line_overrides_environment() {
+        let options = parse(
+            &["--config", "/etc/vdcp.yaml", "--port=9000", "--simulate-adam", "--log-format", "json"],
+            &[("VDCP_PORT", "8080"), ("VDCP_LOG_DIR", "/var/log/vdcp"), ("VDCP_PUBLIC_URL", "http://studio:9000/")],
+        )
+        .unwrap();
+        assert_eq!(options.config_path, PathBuf::from("/etc/vdcp.yaml"));
+        assert_eq!(options.port, 9000);
+        assert_eq!(options.log_dir, PathBuf::from("/var/log/vdcp"));
+        assert_eq!(options.public_url, Some("http://studio:9000".to_string()));
+        assert!(options.simulate_adam);
+        assert_eq!(options.log_format, Some(LogFormat::Json));
+        assert_eq!(options.address, Options::default().address);
+    }
+
+    #[test]
+    fn bad_options_are_refused() {
+        assert!(parse(&["--port", "lots"], &[]).is_err());
+        assert!(parse(&["--config"], &[]).is_err());
+        assert!(parse(&["--colour", "red"], &[]).is_err());
+        assert!(parse(&["config.yaml"], &[]).is_err());
+        assert!(parse(&[], &[("VDCP_PORT", "-1")]).is_err());
+        assert!(parse(&["--log-level", "info,serial=loud"], &[]).is_err());
+        assert!(parse(&["--log-format", "xml"], &[]).is_err());
+        assert_eq!(parse(&[], &[("VDCP_PORT", "")]).unwrap(), Options::default());
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use super::adam::{tally::TallyMapping, AdamCommand, AdamModule, AdamModules, Secret};
+use super::logging::LoggingConfig;
+use super::outputs::{mqtt::MqttConfig, PortOutputs};
+
+pub mod validate;
+
+fn default_coalesce_ms()->u64{
+    11
+}
+#[derive(Serialize, Deserialize, Debug,Clone)]
+pub struct Config {
+    pub ports: Vec<VDCPPort>,
+    ///A single adam output per port. Kept for older configs, `port_outputs` can do the same and more
+    #[serde(default)]
+    pub adam_output_mapping:HashMap<u8,AdamCommand>,
+    ///Every output each port triggers
+    #[serde(default)]
+    pub port_outputs:PortOutputs,
+    ///Address, credentials and protocol of each adam module
+    #[serde(default)]
+    pub adam_modules:AdamModules,
+    ///Older configs only gave each adam module's ip. They are moved into `adam_modules` when loaded
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub adam_ips:HashMap<u8,Ipv4Addr>,
+    ///How long to gather events after the first so simultaneous plays go in one request to each module.
+    ///0 sends every event straight away. Modules can set their own with `coalesce_ms`
+    #[serde(default="default_coalesce_ms")]
+    pub adam_coalesce_ms:u64,
+    ///Broker to publish every port event to
+    #[serde(default)]
+    pub mqtt:Option<MqttConfig>,
+    ///Digital inputs wired to the on-air tally of each port
+    #[serde(default)]
+    pub adam_tally_mapping:TallyMapping,
+    ///Token the web api needs to change the config. Editing is turned off without one
+    #[serde(default)]
+    pub api_token:Option<Secret>,
+    ///Log levels, format and how long logs are kept. Changes need a restart
+    #[serde(default)]
+    pub logging:LoggingConfig,
+}
+impl ::std::default::Default for Config {
+    fn default() -> Self {
+        Self { ports: Vec::new(), adam_modules:HashMap::new(), adam_ips:HashMap::new(),adam_output_mapping:HashMap::new(), port_outputs:HashMap::new(), adam_tally_mapping:HashMap::new(), mqtt:None, adam_coalesce_ms:default_coalesce_ms(), api_token:None, logging:LoggingConfig::default() }
+    }
+}
+impl Config {
+    ///A copy with any passwords hidden so it can be shown to users
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for module in config.adam_modules.values_mut() {
+            module.password = module.password.redacted();
+        }
+        if let Some(mqtt) = config.mqtt.as_mut() {
+            mqtt.password = mqtt.password.as_ref().map(|password| password.redacted());
+        }
+        config.api_token = config.api_token.as_ref().map(|token| token.redacted());
+        config
+    }
+    ///Moves the modules in `adam_ips` into `adam_modules` with the default settings.
+    ///A module in both keeps its `adam_modules` settings. True if there were any
+    pub fn migrate_adam_ips(&mut self) -> bool {
+        if self.adam_ips.is_empty() {
+            return false;
+        }
+        for (id, ip) in self.adam_ips.drain() {
+            self.adam_modules.entry(id).or_insert_with(|| AdamModule {
+                host: ip.to_string(),
+                ..Default::default()
+            });
+        }
+        true
+    }
+    ///Everything the trigger outputs are built from. The outputs are rebuilt when it changes
+    fn output_settings(&self) -> Value {
+        let port_names: HashMap<_, _> = self.ports.iter().map(|port| (port.number, &port.name)).collect();
+        json!({
+            "adam_output_mapping": self.adam_output_mapping,
+            "port_outputs": self.port_outputs,
+            "adam_modules": self.adam_modules,
+            "adam_coalesce_ms": self.adam_coalesce_ms,
+            "adam_tally_mapping": self.adam_tally_mapping,
+            "mqtt": self.mqtt,
+            "port_names": port_names,
+        })
+    }
+}
+
+///What changed between two versions of the config
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ConfigDiff {
+    pub ports_added: Vec<u8>,
+    pub ports_removed: Vec<u8>,
+    ///Ports whose settings changed, they are restarted
+    pub ports_changed: Vec<u8>,
+    pub outputs_changed: bool,
+    ///Each changed setting as `path: old -> new`. A changed password is given as `path: (hidden) changed`
+    pub changes: Vec<String>,
+}
+impl ConfigDiff {
+    pub fn between(old: &Config, new: &Config) -> ConfigDiff {
+        let old_ports: HashMap<_, _> = old.ports.iter().map(|port| (port.number, port)).collect();
+        let new_ports: HashMap<_, _> = new.ports.iter().map(|port| (port.number, port)).collect();
+        let mut diff = ConfigDiff {
+            outputs_changed: old.output_settings() != new.output_settings(),
+            ..Default::default()
+        };
+        for (number, port) in &new_ports {
+            match old_ports.get(number) {
+                None => diff.ports_added.push(*number),
+                Some(old_port) if old_port != port => diff.ports_changed.push(*number),
+                _ => (),
+            }
+        }
+        diff.ports_removed = old_ports.keys().filter(|number| !new_ports.contains_key(number)).copied().collect();
+        diff.ports_added.sort();
+        diff.ports_changed.sort();
+        diff.ports_removed.sort();
+
+        let old_shown = serde_json::to_value(old.redacted()).unwrap_or_default();
+        let new_shown = serde_json::to_value(new.redacted()).unwrap_or_default();
+        let old = serde_json::to_value(old).unwrap_or_default();
+        let new = serde_json::to_value(new).unwrap_or_default();
+        describe_changes("", (&old, &old_shown), (&new, &new_shown), &mut diff.changes);
+        diff
+    }
+    ///True when nothing changed, passwords included
+    pub fn is_empty(&self) -> bool {
+        self.ports_added.is_empty()
+            && self.ports_removed.is_empty()
+            && self.ports_changed.is_empty()
+            && !self.outputs_changed
+            && self.changes.is_empty()
+    }
+}
+impl Display for ConfigDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes");
+        }
+        write!(f, "{:}", self.changes.join(", "))
+    }
+}
+///Lists every value that differs between two json trees by its path. Each tree comes with a copy with its passwords
+///hidden which is what gets written, so a password that changed is listed without its value
+fn describe_changes(path: &str, old: (&Value, &Value), new: (&Value, &Value), changes: &mut Vec<String>) {
+    let child = |key: &str| if path.is_empty() { key.to_string() } else { format!("{:}.{:}", path, key) };
+    let ((old, old_shown), (new, new_shown)) = (old, new);
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            let keys: BTreeSet<_> = old_fields.keys().chain(new_fields.keys()).collect();
+            for key in keys {
+                let key = key.as_str();
+                match (old_fields.get(key), new_fields.get(key)) {
+                    (Some(a), Some(b)) => describe_changes(&child(key), (a, &old_shown[key]), (b, &new_shown[key]), changes),
+                    (None, Some(_)) => changes.push(format!("{:}: added {:}", child(key), new_shown[key])),
+                    (Some(_), None) => changes.push(format!("{:}: removed {:}", child(key), old_shown[key])),
+                    (None, None) => (),
+                }
+            }
+        }
+        //ports are matched up by number so reordering them isn't a change
+        (Value::Array(_), Value::Array(_)) if path == "ports" => {
+            let by_number = |ports: &Value| -> Value {
+                let ports = ports.as_array().map(Vec::as_slice).unwrap_or(&[]);
+                Value::Object(ports.iter().map(|port| (port["number"].to_string(), port.clone())).collect())
+            };
+            describe_changes(path, (&by_number(old), &by_number(old_shown)), (&by_number(new), &by_number(new_shown)), changes)
+        }
+        _ if old == new => (),
+        _ if old_shown == new_shown => changes.push(format!("{:}: (hidden) changed", path)),
+        _ => changes.push(format!("{:}: {:} -> {:}", path, old_shown, new_shown)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VDCPPort {
+    pub port: String,
+    pub number:u8,
+    pub name: String,
+    pub segments:Vec<String>,
+
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(number: u8, segments: &[&str]) -> VDCPPort {
+        VDCPPort {
+            port: format!("/dev/ttyS{:}", number),
+            number,
+            name: format!("port {:}", number),
+            segments: segments.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn diff_finds_port_changes() {
+        let mut old = Config::default();
+        old.ports = vec![port(1, &["first"]), port(2, &["first"]), port(3, &["first"])];
+        let mut new = old.clone();
+        new.ports = vec![port(3, &["first"]), port(1, &["first", "second"]), port(4, &["first"])];
+
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.ports_added, vec![4]);
+        assert_eq!(diff.ports_removed, vec![2]);
+        assert_eq!(diff.ports_changed, vec![1]);
+        //port names are used by the webhooks
+        assert!(diff.outputs_changed);
+        assert!(diff.changes.contains(&r#"ports.1.segments: ["first"] -> ["first","second"]"#.to_string()));
+        assert!(diff.changes.iter().any(|change| change.starts_with("ports.2: removed")));
+    }
+
+    #[test]
+    fn diff_hides_passwords() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.api_token = Some(Secret::Plain("hunter2".to_string()));
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.changes, vec![r#"api_token: null -> "***""#.to_string()]);
+        assert!(!diff.outputs_changed);
+        assert!(ConfigDiff::between(&old, &old.clone()).is_empty());
+    }
+
+    #[test]
+    fn rotating_only_a_password_is_a_change() {
+        let mut old = Config::default();
+        old.adam_modules.insert(0, AdamModule {
+            host: "10.0.0.1".to_string(),
+            password: Secret::Plain("old".to_string()),
+            ..Default::default()
+        });
+        old.api_token = Some(Secret::Plain("old".to_string()));
+        let mut new = old.clone();
+        new.adam_modules.get_mut(&0).unwrap().password = Secret::Plain("new".to_string());
+        new.api_token = Some(Secret::Plain("new".to_string()));
+
+        let diff = ConfigDiff::between(&old, &new);
+        assert!(!diff.is_empty());
+        assert!(diff.outputs_changed, "the adam output logs in with the new password");
+        assert_eq!(
+            diff.changes,
+            vec![
+                "adam_modules.0.password: (hidden) changed".to_string(),
+                "api_token: (hidden) changed".to_string(),
+            ]
+        );
+        assert!(!diff.to_string().contains("new"));
+    }
+}
@@ -0,0 +1,529 @@
+//===Config validation===
+//Checks the whole config and reports every problem with its yaml path, rather than stopping at the first one.
+//Errors stop a config from being used, warnings are settings that work but probably aren't what was meant.
+use log::{error, warn};
+use serde::Serialize;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+use super::Config;
+use crate::adam::{AdamCommand, AdamProtocol, OutputMode, Scheme};
+use crate::logging;
+use crate::outputs::{self, OutputConfig};
+use crate::vdcp::types::EventKind;
+
+///Coalescing windows longer than this hold plays back long enough to be noticed on air
+const LARGE_COALESCE_MS: u64 = 100;
+///Reading the tally inputs more often than this loads the modules that are also setting outputs
+const FAST_TALLY_POLL_MS: u64 = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+///Something wrong with the setting at `path`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    ///Where the setting is in the yaml, like `port_outputs.1[0].adam_module`. Empty for the file as a whole
+    pub path: String,
+    pub message: String,
+}
+impl Problem {
+    fn error(path: impl Into<String>, message: impl Into<String>) -> Problem {
+        Problem { severity: Severity::Error, path: path.into(), message: message.into() }
+    }
+    fn warning(path: impl Into<String>, message: impl Into<String>) -> Problem {
+        Problem { severity: Severity::Warning, path: path.into(), message: message.into() }
+    }
+}
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        if self.path.is_empty() {
+            write!(f, "{:}: {:}", severity, self.message)
+        } else {
+            write!(f, "{:} at {:}: {:}", severity, self.path, self.message)
+        }
+    }
+}
+
+pub fn has_errors(problems: &[Problem]) -> bool {
+    problems.iter().any(|problem| problem.severity == Severity::Error)
+}
+///Logs the warnings and turns any errors into one message, for refusing the config
+pub fn refuse_errors(problems: &[Problem]) -> Result<(), String> {
+    for problem in problems.iter().filter(|problem| problem.severity == Severity::Warning) {
+        warn!("Config {:}", problem);
+    }
+    let errors: Vec<_> = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .map(|problem| problem.to_string())
+        .collect();
+    if errors.is_empty() {
+        return Ok(());
+    }
+    error!("The config has {:} errors: {:}", errors.len(), errors.join("; "));
+    Err(errors.join("; "))
+}
+
+///Reads and checks a config file. The config is only given back if it could be parsed
+pub fn check_file(path: &Path) -> (Option<Config>, Vec<Problem>) {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => return (None, vec![Problem::error("", format!("couldn't read {:?}: {:}", path, e))]),
+    };
+    check_text(&text)
+}
+fn check_text(text: &str) -> (Option<Config>, Vec<Problem>) {
+    //serde_yaml starts its messages with the path of the setting it failed on
+    let mut config: Config = match serde_yaml::from_str(text) {
+        Ok(config) => config,
+        Err(e) => return (None, vec![Problem::error("", e.to_string())]),
+    };
+    let mut problems = Vec::new();
+    //anything that doesn't survive being parsed and written back out isn't a setting we know
+    if let (Ok(raw), Ok(known)) = (serde_yaml::from_str::<Value>(text), serde_yaml::to_value(&config)) {
+        unknown_keys("", &raw, &known, &mut problems);
+    }
+    if config.migrate_adam_ips() {
+        problems.push(Problem::warning(
+            "adam_ips",
+            "is replaced by adam_modules, its modules are used with the default settings",
+        ));
+    }
+    problems.extend(check(&config));
+    (Some(config), problems)
+}
+
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.clone(),
+        Value::Number(key) => key.to_string(),
+        Value::Bool(key) => key.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+fn child(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{:}.{:}", path, key)
+    }
+}
+fn unknown_keys(path: &str, raw: &Value, known: &Value, problems: &mut Vec<Problem>) {
+    match (raw, known) {
+        (Value::Mapping(raw), Value::Mapping(known)) => {
+            let known: HashMap<_, _> = known.iter().map(|(key, value)| (key_name(key), value)).collect();
+            for (key, value) in raw {
+                let key = key_name(key);
+                match known.get(&key) {
+                    Some(known) => unknown_keys(&child(path, &key), value, known, problems),
+                    None => problems.push(Problem::warning(child(path, &key), "unknown setting, it is ignored")),
+                }
+            }
+        }
+        (Value::Sequence(raw), Value::Sequence(known)) => {
+            for (index, (raw, known)) in raw.iter().zip(known).enumerate() {
+                unknown_keys(&format!("{:}[{:}]", path, index), raw, known, problems);
+            }
+        }
+        _ => (),
+    }
+}
+
+///Map entries in key order so problems are always listed the same way
+fn sorted<K: Ord + Copy, V>(map: &HashMap<K, V>) -> BTreeMap<K, &V> {
+    map.iter().map(|(key, value)| (*key, value)).collect()
+}
+
+///Checks that every setting makes sense and that the parts of the config agree with each other
+pub fn check(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    check_ports(config, &mut problems);
+    for (port, command) in sorted(&config.adam_output_mapping) {
+        check_command(&format!("adam_output_mapping.{:}", port), command, config, &mut problems);
+    }
+    for (port, outputs) in sorted(&config.port_outputs) {
+        for (index, output) in outputs.iter().enumerate() {
+            let path = format!("port_outputs.{:}[{:}]", port, index);
+            match output {
+                OutputConfig::Adam(command) => check_command(&path, command, config, &mut problems),
+                OutputConfig::Webhook(hook) => {
+                    if !(hook.url.starts_with("http://") || hook.url.starts_with("https://")) {
+                        problems.push(Problem::error(child(&path, "url"), format!("{:?} isn't an http or https url", hook.url)));
+                    }
+                    if hook.events.is_empty() {
+                        problems.push(Problem::warning(child(&path, "events"), "no events are listed so the hook is never called"));
+                    }
+                }
+                OutputConfig::Osc(message) => {
+                    if !is_host_and_port(&message.target) {
+                        problems.push(Problem::error(child(&path, "target"), format!("{:?} should be given as ip:port", message.target)));
+                    }
+                    if !message.address.starts_with('/') {
+                        problems.push(Problem::error(child(&path, "address"), "OSC addresses start with /"));
+                    }
+                    if message.events.is_empty() {
+                        problems.push(Problem::warning(child(&path, "events"), "no events are listed so the message is never sent"));
+                    }
+                }
+            }
+        }
+    }
+    check_modules(config, &mut problems);
+    for (port, input) in sorted(&config.adam_tally_mapping) {
+        if !config.adam_modules.contains_key(&input.adam_module) {
+            problems.push(Problem::error(
+                format!("adam_tally_mapping.{:}.adam_module", port),
+                format!("adam module {:} isn't in adam_modules", input.adam_module),
+            ));
+        }
+    }
+    if let Some(mqtt) = &config.mqtt {
+        if !is_host_and_port(&mqtt.broker) {
+            problems.push(Problem::error("mqtt.broker", format!("{:?} should be given as host:port", mqtt.broker)));
+        }
+        if let Some(Err(e)) = mqtt.password.as_ref().map(|password| password.resolve()) {
+            problems.push(Problem::error("mqtt.password", e));
+        }
+    }
+    check_logging(config, &mut problems);
+    if let Some(Err(e)) = config.api_token.as_ref().map(|token| token.resolve()) {
+        problems.push(Problem::warning("api_token", format!("{:}, editing through the web api will be off", e)));
+    }
+    problems
+}
+
+fn check_ports(config: &Config, problems: &mut Vec<Problem>) {
+    let mut seen = HashMap::new();
+    for (index, port) in config.ports.iter().enumerate() {
+        let path = format!("ports[{:}]", index);
+        if let Some(first) = seen.insert(port.number, index) {
+            problems.push(Problem::error(
+                child(&path, "number"),
+                format!("port number {:} is already used by ports[{:}]", port.number, first),
+            ));
+            seen.insert(port.number, first);
+        }
+        if port.port.trim().is_empty() {
+            problems.push(Problem::error(child(&path, "port"), "no serial port is set"));
+        } else if cfg!(unix) && !Path::new(&port.port).exists() {
+            problems.push(Problem::warning(child(&path, "port"), format!("{:} doesn't exist, the port will fail to open until it does", port.port)));
+        }
+        if port.segments.is_empty() {
+            problems.push(Problem::warning(child(&path, "segments"), "the port has no clips to cue"));
+        }
+    }
+
+    let mapped: HashSet<u8> = config
+        .adam_output_mapping
+        .keys()
+        .chain(config.port_outputs.keys())
+        .chain(config.adam_tally_mapping.keys())
+        .copied()
+        .collect();
+    //mqtt publishes every port
+    if config.mqtt.is_none() {
+        for (index, port) in config.ports.iter().enumerate() {
+            if !mapped.contains(&port.number) {
+                problems.push(Problem::warning(
+                    format!("ports[{:}]", index),
+                    format!("port {:} has no outputs, its plays won't trigger anything", port.number),
+                ));
+            }
+        }
+    }
+    let configured: HashSet<u8> = config.ports.iter().map(|port| port.number).collect();
+    let mappings = [
+        ("adam_output_mapping", config.adam_output_mapping.keys().copied().collect::<Vec<_>>()),
+        ("port_outputs", config.port_outputs.keys().copied().collect()),
+        ("adam_tally_mapping", config.adam_tally_mapping.keys().copied().collect()),
+    ];
+    for (name, mut ports) in mappings.iter().cloned() {
+        ports.sort();
+        for port in ports.into_iter().filter(|port| !configured.contains(port)) {
+            problems.push(Problem::warning(
+                format!("{:}.{:}", name, port),
+                format!("there is no port {:} in ports, this is only used if a controller selects it as a logical port", port),
+            ));
+        }
+    }
+}
+
+fn check_command(path: &str, command: &AdamCommand, config: &Config, problems: &mut Vec<Problem>) {
+    if !config.adam_modules.contains_key(&command.adam_module) {
+        problems.push(Problem::error(
+            child(path, "adam_module"),
+            format!("adam module {:} isn't in adam_modules", command.adam_module),
+        ));
+    }
+    if command.mode == OutputMode::Pulse && command.pulse_ms == 0 {
+        problems.push(Problem::warning(child(path, "pulse_ms"), "a 0ms pulse turns the output straight back off"));
+    }
+    if command.events.is_empty() {
+        problems.push(Problem::warning(child(path, "events"), "no events are listed so the output never fires"));
+    } else if command.schedule.is_some() && !command.events.contains(&EventKind::Play) {
+        problems.push(Problem::warning(
+            child(path, "schedule"),
+            "scheduled outputs fire from a play, but play isn't in events so it never fires",
+        ));
+    }
+}
+
+fn check_modules(config: &Config, problems: &mut Vec<Problem>) {
+    if config.adam_coalesce_ms > LARGE_COALESCE_MS {
+        problems.push(Problem::warning(
+            "adam_coalesce_ms",
+            format!("plays are held back {:}ms before being sent", config.adam_coalesce_ms),
+        ));
+    }
+    let mapping = outputs::adam_mapping(config);
+    for (id, module) in sorted(&config.adam_modules) {
+        let path = format!("adam_modules.{:}", id);
+        if module.host.trim().is_empty() {
+            problems.push(Problem::error(child(&path, "host"), "no host is set"));
+        }
+        if module.port == Some(0) {
+            problems.push(Problem::error(child(&path, "port"), "port 0 can't be connected to"));
+        }
+        match module.protocol {
+            AdamProtocol::Rest if module.username.is_empty() => {
+                problems.push(Problem::error(child(&path, "username"), "the REST api needs a username"))
+            }
+            AdamProtocol::Modbus(_) if module.scheme == Scheme::Https => problems.push(Problem::warning(
+                child(&path, "scheme"),
+                "the module uses modbus, the https scheme is ignored",
+            )),
+            _ => (),
+        }
+        if let AdamProtocol::Modbus(settings) = &module.protocol {
+            let outputs = mapping.values().flatten().filter(|command| command.adam_module == *id);
+            let last_output = outputs.map(|command| command.digital_output_number).max();
+            if let Some(last) = last_output.filter(|last| settings.coil_offset.checked_add(*last as u16).is_none()) {
+                problems.push(Problem::error(
+                    child(&path, "protocol.coil_offset"),
+                    format!("output {:} would be past the last modbus coil {:}", last, u16::MAX),
+                ));
+            }
+            let inputs = config.adam_tally_mapping.values().filter(|input| input.adam_module == *id);
+            let last_input = inputs.map(|input| input.digital_input_number).max();
+            if let Some(last) = last_input.filter(|last| settings.input_offset.checked_add(*last as u16).is_none()) {
+                problems.push(Problem::error(
+                    child(&path, "protocol.input_offset"),
+                    format!("input {:} would be past the last modbus input {:}", last, u16::MAX),
+                ));
+            }
+        }
+        let has_tallies = config.adam_tally_mapping.values().any(|input| input.adam_module == *id);
+        if has_tallies && module.tally_poll_ms == 0 {
+            problems.push(Problem::error(child(&path, "tally_poll_ms"), "the tally inputs need time between reads"));
+        } else if has_tallies && module.tally_poll_ms < FAST_TALLY_POLL_MS {
+            problems.push(Problem::warning(
+                child(&path, "tally_poll_ms"),
+                format!("the tally inputs are read every {:}ms, which takes time from the outputs on the module", module.tally_poll_ms),
+            ));
+        }
+        if let Err(e) = module.password.resolve() {
+            problems.push(Problem::error(child(&path, "password"), e));
+        }
+        let timing = &module.timing;
+        if timing.queue_capacity == 0 {
+            problems.push(Problem::error(child(&path, "timing.queue_capacity"), "the queue needs room for at least one request"));
+        }
+        if timing.stale_after_ms < timing.connect_timeout_ms + timing.read_timeout_ms {
+            problems.push(Problem::warning(
+                child(&path, "timing.stale_after_ms"),
+                "shorter than a single request can take, requests queued behind a slow one will be dropped",
+            ));
+        }
+        if let Some(ms) = module.coalesce_ms.filter(|ms| *ms > LARGE_COALESCE_MS) {
+            problems.push(Problem::warning(
+                child(&path, "coalesce_ms"),
+                format!("plays are held back {:}ms before being sent", ms),
+            ));
+        }
+    }
+}
+
+fn check_logging(config: &Config, problems: &mut Vec<Problem>) {
+    let logging = &config.logging;
+    let levels = std::iter::once(("logging.level".to_string(), &logging.level))
+        .chain(logging.modules.iter().map(|(module, level)| (format!("logging.modules.{:}", module), level)));
+    for (path, level) in levels {
+        if !logging::is_level(level) {
+            problems.push(Problem::error(path, format!("{:} isn't a log level, use off, error, warn, info, debug or trace", level)));
+        }
+    }
+    if logging.retention.max_size_mb == 0 {
+        problems.push(Problem::error("logging.retention.max_size_mb", "log files need to be allowed some size"));
+    }
+    if logging.retention.keep_files == 0 {
+        problems.push(Problem::warning("logging.retention.keep_files", "no finished log files will be kept"));
+    }
+}
+
+///Whether an address is given as `host:port`
+fn is_host_and_port(address: &str) -> bool {
+    match address.rsplitn(2, ':').collect::<Vec<_>>().as_slice() {
+        [port, host] => !host.is_empty() && port.parse::<u16>().map_or(false, |port| port != 0),
+        _ => false,
+    }
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+ports: [
+  { port: "", name: "a", number: 1, segments: [] },
+  { port: "/dev/null", name: "b", number: 1, segments: ["first"] },
+]
+port_outputs: {
+  1: [ { type: adam, adam_module: 3, digital_output_number: 0, pulse_ms: 0, colour: red },
+       { type: osc, target: "10.0.0.6", address: "go" } ],
+  5: [ { type: webhook, url: "10.0.0.5/take" } ],
+}
+adam_modules: {
+  0: { host: "", timing: { queue_capacity: 0 } },
+}
+adam_coalesce_ms: 500
+logging: { level: loud, modules: { serial: debug } }
+"#;
+
+    fn paths(problems: &[Problem], severity: Severity) -> Vec<&str> {
+        problems
+            .iter()
+            .filter(|problem| problem.severity == severity)
+            .map(|problem| problem.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn every_problem_is_found_with_its_path() {
+        let (config, problems) = check_text(CONFIG);
+        assert!(config.is_some());
+        assert!(has_errors(&problems));
+        assert_eq!(
+            paths(&problems, Severity::Error),
+            vec![
+                "ports[0].port",
+                "ports[1].number",
+                "port_outputs.1[0].adam_module",
+                "port_outputs.1[1].target",
+                "port_outputs.1[1].address",
+                "port_outputs.5[0].url",
+                "adam_modules.0.host",
+                "adam_modules.0.timing.queue_capacity",
+                "logging.level",
+            ]
+        );
+        let warnings = paths(&problems, Severity::Warning);
+        for path in &[
+            "port_outputs.1[0].colour",
+            "ports[0].segments",
+            "port_outputs.5",
+            "port_outputs.1[0].pulse_ms",
+            "adam_coalesce_ms",
+        ] {
+            assert!(warnings.contains(path), "no warning for {:}: {:?}", path, warnings);
+        }
+        assert_eq!(
+            problems[0].to_string(),
+            "warning at port_outputs.1[0].colour: unknown setting, it is ignored"
+        );
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_setting() {
+        let (config, problems) = check_text("ports: [ { port: \"/dev/null\", name: a, number: one, segments: [] } ]\nadam_modules: {}");
+        assert!(config.is_none());
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].message.starts_with("ports[0].number"), "{:}", problems[0]);
+    }
+
+    #[test]
+    fn good_config_has_no_problems() {
+        let (_, problems) = check_text(
+            r#"
+ports: [ { port: "/dev/null", name: a, number: 1, segments: ["first"] } ]
+adam_output_mapping: { 1: { adam_module: 0, digital_output_number: 0 } }
+adam_modules: { 0: { host: "10.0.0.1" } }
+"#,
+        );
+        assert_eq!(problems, vec![]);
+        assert!(refuse_errors(&problems).is_ok());
+    }
+
+    #[test]
+    fn tally_polling_needs_a_pause() {
+        let config = |poll_ms: u64| {
+            format!(
+                r#"
+ports: [ {{ port: "/dev/null", name: a, number: 1, segments: ["first"] }} ]
+adam_tally_mapping: {{ 1: {{ adam_module: 0, digital_input_number: 0 }} }}
+adam_modules: {{ 0: {{ host: "10.0.0.1", tally_poll_ms: {:} }} }}
+"#,
+                poll_ms
+            )
+        };
+        assert_eq!(paths(&check_text(&config(0)).1, Severity::Error), vec!["adam_modules.0.tally_poll_ms"]);
+        assert_eq!(paths(&check_text(&config(25)).1, Severity::Warning), vec!["adam_modules.0.tally_poll_ms"]);
+        assert!(check_text(&config(200)).1.is_empty());
+    }
+
+    #[test]
+    fn schedules_that_never_fire_are_flagged() {
+        let (_, problems) = check_text(
+            r#"
+ports: [ { port: "/dev/null", name: a, number: 1, segments: ["first"] } ]
+adam_output_mapping: { 1: { adam_module: 0, digital_output_number: 0, events: [stop], schedule: { after_play_ms: 500 } } }
+adam_modules: { 0: { host: "10.0.0.1" } }
+"#,
+        );
+        assert_eq!(paths(&problems, Severity::Warning), vec!["adam_output_mapping.1.schedule"]);
+    }
+
+    #[test]
+    fn modbus_offsets_past_the_last_address_are_refused() {
+        let (_, problems) = check_text(
+            r#"
+ports: [ { port: "/dev/null", name: a, number: 1, segments: ["first"] } ]
+adam_output_mapping: { 1: { adam_module: 0, digital_output_number: 2 } }
+adam_tally_mapping: { 1: { adam_module: 0, digital_input_number: 0 } }
+adam_modules: { 0: { host: "10.0.0.1", protocol: { type: modbus, coil_offset: 65534, input_offset: 65535 } } }
+"#,
+        );
+        assert_eq!(paths(&problems, Severity::Error), vec!["adam_modules.0.protocol.coil_offset"]);
+    }
+
+    #[test]
+    fn adam_ips_from_older_configs_still_load() {
+        let (config, problems) = check_text(
+            r#"
+ports: [ { port: "/dev/null", name: a, number: 1, segments: ["first"] } ]
+adam_output_mapping: { 1: { adam_module: 0, digital_output_number: 0 } }
+adam_ips: { 0: "10.0.0.1" }
+"#,
+        );
+        let config = config.unwrap();
+        assert_eq!(paths(&problems, Severity::Warning), vec!["adam_ips"]);
+        assert!(!has_errors(&problems));
+        assert_eq!(config.adam_modules[&0].host, "10.0.0.1");
+        assert!(config.adam_ips.is_empty(), "the migrated config is saved without adam_ips");
+    }
+}
@@ -0,0 +1,128 @@
+//===Port event bus===
+//Every vdcp handler publishes what happens on its port here. The outputs, web server and logging each
+//subscribe and get their own copy of every event, so a slow subscriber never holds up the others.
+use log::info;
+use serde::Serializer;
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::vdcp::types::PortEvent;
+
+///How many events are kept for the web api
+const RECENT_LENGTH: usize = 200;
+
+pub fn unix_time_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+pub fn serialize_unix_ms<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(unix_time_ms(*time))
+}
+
+///Cheap to clone, every clone publishes to the same subscribers
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<PortEvent>>>>,
+}
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+    ///Every event published after this is sent to the returned receiver.
+    ///Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<PortEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+    pub fn publish(&self, event: PortEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+///The most recent events, shared with the web server
+#[derive(Default)]
+pub struct RecentEvents {
+    events: Mutex<VecDeque<PortEvent>>,
+}
+impl RecentEvents {
+    fn record(&self, event: PortEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == RECENT_LENGTH {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+    ///Newest first
+    pub fn recent(&self) -> Vec<PortEvent> {
+        self.events.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+///Keeps the most recent events from the bus for the web server
+pub fn record_recent(bus: &EventBus) -> Arc<RecentEvents> {
+    let recent = Arc::new(RecentEvents::default());
+    let events = bus.subscribe();
+    let recorder = recent.clone();
+    thread::spawn(move || {
+        for event in events.iter() {
+            recorder.record(event);
+        }
+    });
+    recent
+}
+///Logs every event on the bus
+pub fn log_events(bus: &EventBus) {
+    let events = bus.subscribe();
+    thread::spawn(move || {
+        for event in events.iter() {
+            info!(
+                "[Port:{:}]{{Event}} {:} on logical port {:} clip {:?}{:}",
+                event.port,
+                event.kind.as_str(),
+                event.logical_port,
+                event.clip,
+                event.detail.map(|d| format!(" ({:})", d)).unwrap_or_default()
+            );
+        }
+    });
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vdcp::types::EventKind;
+
+    #[test]
+    fn every_subscriber_gets_every_event() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        bus.publish(PortEvent::new(EventKind::Play, 1, "first"));
+        bus.clone().publish(PortEvent::new(EventKind::Stop, 1, "first"));
+        for subscriber in &[first, second] {
+            let kinds: Vec<_> = subscriber.try_iter().map(|event| event.kind).collect();
+            assert_eq!(kinds, vec![EventKind::Play, EventKind::Stop]);
+        }
+    }
+    #[test]
+    fn dropped_subscribers_are_removed() {
+        let bus = EventBus::new();
+        let kept = bus.subscribe();
+        drop(bus.subscribe());
+        bus.publish(PortEvent::new(EventKind::Cue, 2, "first"));
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(kept.try_iter().count(), 1);
+    }
+}
@@ -0,0 +1,347 @@
+//===Logging===
+//Everything is logged to the log directory, and to the console unless turned off. Everything at debug and above also goes
+//to the debug log directory. Levels can be set for single modules, so the frame dumps of `serial` can be turned on
+//without the rest of the program getting as noisy.
+//
+//Port threads mark their logs with their port number, and the frame being handled, which json logs give as fields.
+use flexi_logger::{Age, Cleanup, Criterion, DeferredNow, Duplicate, LogTarget, Logger, Naming};
+use itertools::Itertools;
+use log::{info, warn, Level, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::cli::Options;
+
+///Module names that are given without the crate name in the config
+const OWN_MODULES: [&str; 11] = [
+    "adam", "cli", "config", "events", "logging", "main", "outputs", "runtime", "serial", "vdcp", "web_server",
+];
+const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+///How often old log files are looked for
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn default_level() -> String {
+    "info".to_string()
+}
+fn default_console() -> bool {
+    true
+}
+fn default_max_size_mb() -> u64 {
+    10
+}
+fn default_keep_files() -> usize {
+    100
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    ///One json object per line with the time, level, module, message and the port and frame if there are any
+    Json,
+}
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+impl LogFormat {
+    pub fn parse(format: &str) -> Result<LogFormat, String> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("{:} isn't a log format, use text or json", format)),
+        }
+    }
+}
+
+///How long log files are kept
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Retention {
+    ///A new file is started each day or when the current one reaches this size
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    ///How many files each log directory keeps, the oldest are deleted
+    #[serde(default = "default_keep_files")]
+    pub keep_files: usize,
+    ///Files older than this are deleted
+    #[serde(default)]
+    pub keep_days: Option<u64>,
+}
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_size_mb: default_max_size_mb(),
+            keep_files: default_keep_files(),
+            keep_days: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct LoggingConfig {
+    ///The level of everything without a level in `modules`
+    #[serde(default = "default_level")]
+    pub level: String,
+    ///Levels of single modules, like `serial: debug`. Modules of other crates like `rocket` can be given too
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+    #[serde(default)]
+    pub format: LogFormat,
+    ///Also writes the logs to the console. Warnings and errors always are
+    #[serde(default = "default_console")]
+    pub console: bool,
+    #[serde(default)]
+    pub retention: Retention,
+}
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_level(),
+            modules: BTreeMap::new(),
+            format: LogFormat::default(),
+            console: default_console(),
+            retention: Retention::default(),
+        }
+    }
+}
+impl LoggingConfig {
+    ///Applies a spec like `info,serial=debug`, as given on the command line
+    fn apply_spec(&mut self, spec: &str) -> Result<(), String> {
+        let (level, modules) = parse_spec(spec)?;
+        if let Some(level) = level {
+            self.level = level;
+        }
+        self.modules.extend(modules);
+        Ok(())
+    }
+    ///The flexi_logger spec with `base` as the level of modules without their own
+    fn spec(&self, base: &str) -> String {
+        std::iter::once(base.to_string())
+            .chain(self.modules.iter().map(|(module, level)| format!("{:}={:}", module_path(module), level)))
+            .join(", ")
+    }
+    fn uses_trace(&self) -> bool {
+        std::iter::once(&self.level).chain(self.modules.values()).any(|level| level.eq_ignore_ascii_case("trace"))
+    }
+}
+
+pub fn is_level(level: &str) -> bool {
+    LEVELS.iter().any(|known| known.eq_ignore_ascii_case(level))
+}
+///Splits a spec like `info,serial=debug` into the overall level and the level of each module
+pub fn parse_spec(spec: &str) -> Result<(Option<String>, Vec<(String, String)>), String> {
+    let mut level = None;
+    let mut modules = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (module, part_level) = match part.find('=') {
+            Some(split) => (Some(part[..split].trim()), part[split + 1..].trim()),
+            None => (None, part),
+        };
+        if !is_level(part_level) {
+            return Err(format!("{:} isn't a log level, use one of {:}", part_level, LEVELS.join(", ")));
+        }
+        match module {
+            Some(module) => modules.push((module.to_string(), part_level.to_string())),
+            None => level = Some(part_level.to_string()),
+        }
+    }
+    Ok((level, modules))
+}
+///The full path of a module, modules of this program can be given by their name alone
+fn module_path(module: &str) -> String {
+    let first = module.split("::").next().unwrap_or(module);
+    if OWN_MODULES.contains(&first) {
+        format!("vdcp_spoof::{:}", module)
+    } else {
+        module.to_string()
+    }
+}
+
+///Starts logging with the config's settings, overridden by any given on the command line
+pub fn setup(options: &Options, config: &LoggingConfig) -> Result<(), String> {
+    let mut config = config.clone();
+    if let Some(spec) = &options.log_level {
+        config.apply_spec(spec)?;
+    }
+    if let Some(format) = options.log_format {
+        config.format = format;
+    }
+    let format: fn(&mut dyn Write, &mut DeferredNow, &Record) -> io::Result<()> = match config.format {
+        LogFormat::Text => flexi_logger::opt_format,
+        LogFormat::Json => json_format,
+    };
+    let rotate = |logger: Logger| {
+        logger.rotate(
+            Criterion::AgeOrSize(Age::Day, config.retention.max_size_mb.saturating_mul(1000 * 1000)),
+            Naming::Timestamps,
+            Cleanup::KeepLogFiles(config.retention.keep_files),
+        )
+    };
+    let console = if config.console { Duplicate::All } else { Duplicate::None };
+    let (main, _) = rotate(Logger::with_str(config.spec(&config.level)))
+        .log_target(LogTarget::File)
+        .format(format)
+        .directory(options.log_dir.clone())
+        .duplicate_to_stdout(console) // write logs to file
+        .duplicate_to_stderr(Duplicate::Warn) // print warnings and errors also to the console
+        .build()
+        .map_err(|e| format!("couldn't start logging to {:?}: {:}", options.log_dir, e))?;
+    let debug_level = if config.level.eq_ignore_ascii_case("trace") { "trace" } else { "debug" };
+    let (debug, _) = rotate(Logger::with_str(config.spec(debug_level)))
+        .log_target(LogTarget::File)
+        .format(format)
+        .directory(options.debug_log_dir.clone())
+        .build()
+        .map_err(|e| format!("couldn't start logging to {:?}: {:}", options.debug_log_dir, e))?;
+    let max_level = if config.uses_trace() { Level::Trace } else { Level::Debug };
+    multi_log::MultiLogger::init(vec![main, debug], max_level).map_err(|e| e.to_string())?;
+    info!("Logging at {:} to {:?} and at {:} to {:?}", config.spec(&config.level), options.log_dir, config.spec(debug_level), options.debug_log_dir);
+
+    if let Some(days) = config.retention.keep_days {
+        let dirs = vec![options.log_dir.clone(), options.debug_log_dir.clone()];
+        let keep = Duration::from_secs(days * 24 * 60 * 60);
+        thread::spawn(move || loop {
+            remove_old_logs(&dirs, keep);
+            thread::sleep(CLEANUP_INTERVAL);
+        });
+    }
+    Ok(())
+}
+
+///Deletes the log files in `dirs` that haven't been written to for longer than `keep`. The files being written are kept
+fn remove_old_logs(dirs: &[PathBuf], keep: Duration) {
+    let now = SystemTime::now();
+    for dir in dirs {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Couldn't look for old logs in {:?}: {:}", dir, e);
+                continue;
+            }
+        };
+        for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+            if !name.ends_with(".log") || name.contains("rCURRENT") {
+                continue;
+            }
+            let age = std::fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok());
+            if age.map_or(false, |age| age >= keep) {
+                match std::fs::remove_file(&path) {
+                    Ok(()) => info!("Removed old log file {:?}", path),
+                    Err(e) => warn!("Couldn't remove old log file {:?}: {:}", path, e),
+                }
+            }
+        }
+    }
+}
+
+thread_local! {
+    static PORT: Cell<Option<u8>> = Cell::new(None);
+    static FRAME: RefCell<Option<String>> = RefCell::new(None);
+}
+///Marks everything logged on this thread as being about `port`
+pub fn set_port(port: u8) {
+    PORT.with(|current| current.set(Some(port)));
+}
+///Marks everything logged on this thread as being about `frame`, until the returned guard is dropped
+pub fn frame_context(frame: &[u8]) -> FrameContext {
+    let hex = frame.iter().map(|byte| format!("{:02x}", byte)).join(" ");
+    FRAME.with(|current| *current.borrow_mut() = Some(hex));
+    FrameContext
+}
+pub struct FrameContext;
+impl Drop for FrameContext {
+    fn drop(&mut self) {
+        FRAME.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+fn json_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+    write!(w, "{:}", json_line(now.now().to_rfc3339(), record))
+}
+fn json_line(time: String, record: &Record) -> Value {
+    let mut line = json!({
+        "time": time,
+        "level": record.level().to_string(),
+        "module": record.module_path().unwrap_or(""),
+        "message": record.args().to_string(),
+    });
+    if let Some(port) = PORT.with(|port| port.get()) {
+        line["port"] = json!(port);
+    }
+    if let Some(frame) = FRAME.with(|frame| frame.borrow().clone()) {
+        line["frame"] = json!(frame);
+    }
+    line
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_levels() {
+        let mut config = LoggingConfig::default();
+        config.modules.insert("rocket".to_string(), "warn".to_string());
+        config.apply_spec("debug, serial=trace").unwrap();
+        assert_eq!(config.spec(&config.level), "debug, rocket=warn, vdcp_spoof::serial=trace");
+        assert!(config.uses_trace());
+        assert!(config.apply_spec("serial=loud").is_err());
+        assert!(parse_spec("").unwrap() == (None, vec![]));
+    }
+
+    #[test]
+    fn json_lines_have_the_port_and_frame() {
+        let line = |message: &str| {
+            json_line(
+                "now".to_string(),
+                &Record::builder()
+                    .args(format_args!("{:}", message))
+                    .level(Level::Debug)
+                    .module_path(Some("vdcp_spoof::serial"))
+                    .build(),
+            )
+        };
+        assert_eq!(line("hello").get("port"), None);
+        set_port(3);
+        {
+            let _frame = frame_context(&[0x02, 0x02, 0x10, 0x01, 0xef]);
+            let logged = line("play");
+            assert_eq!(logged["port"], 3);
+            assert_eq!(logged["frame"], "02 02 10 01 ef");
+            assert_eq!(logged["level"], "DEBUG");
+            assert_eq!(logged["message"], "play");
+        }
+        assert_eq!(line("after").get("frame"), None);
+    }
+
+    #[test]
+    fn only_old_closed_logs_are_removed() {
+        let dir = std::env::temp_dir().join(format!("vdcp-spoof-logs-{:}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in &["old.log", "vdcp_rCURRENT.log", "notes.txt"] {
+            std::fs::write(dir.join(name), "x").unwrap();
+        }
+        remove_old_logs(&[dir.clone()], Duration::from_secs(3600));
+        assert!(dir.join("old.log").exists());
+        remove_old_logs(&[dir.clone()], Duration::from_secs(0));
+        assert!(!dir.join("old.log").exists());
+        assert!(dir.join("vdcp_rCURRENT.log").exists());
+        assert!(dir.join("notes.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+//===Trigger outputs===
+//Every event from the vdcp ports is routed to each output that is configured for that port.
+//New kinds of output only need to implement `TriggerOutput` and be added to `OutputConfig`.
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::adam::{AdamCommand, CommandMapping};
+use crate::config::Config;
+use crate::vdcp::types::PortEvent;
+use mqtt::MqttOutput;
+use osc::{OscConfig, OscOutput};
+use webhook::{WebhookConfig, WebhookOutput};
+
+pub mod mqtt;
+pub mod osc;
+pub mod webhook;
+
+///Something that acts on port events. Each output runs on its own thread.
+pub trait TriggerOutput: Send {
+    ///Used in logs
+    fn name(&self) -> String;
+    ///The ports this output wants events from
+    fn ports(&self) -> HashSet<u8>;
+    ///The settings it was built from. When the outputs are replaced, one with the same name and settings keeps running
+    ///instead of being restarted. None always restarts it
+    fn settings(&self) -> Option<Value> {
+        None
+    }
+    ///Handles events until the channel closes
+    fn run(self: Box<Self>, events: Receiver<PortEvent>);
+}
+
+///One output of a port, as written in the config
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputConfig {
+    Adam(AdamCommand),
+    Webhook(WebhookConfig),
+    Osc(OscConfig),
+}
+///The outputs of each port by port number
+pub type PortOutputs = HashMap<u8, Vec<OutputConfig>>;
+
+///The adam commands of every port, from both `adam_output_mapping` and the adam outputs in `port_outputs`
+pub fn adam_mapping(config: &Config) -> CommandMapping {
+    let mut mapping = CommandMapping::new();
+    for (port, command) in &config.adam_output_mapping {
+        mapping.entry(*port).or_default().push(command.clone());
+    }
+    for (port, outputs) in &config.port_outputs {
+        for output in outputs {
+            match output {
+                OutputConfig::Adam(command) => mapping.entry(*port).or_default().push(command.clone()),
+                _ => (),
+            }
+        }
+    }
+    mapping
+}
+
+///Builds every kind of output that is used in `port_outputs` and mqtt if it is set up, apart from adam which needs state shared
+///with the rest of the program and is built in main
+pub fn from_config(config: &Config) -> Vec<Box<dyn TriggerOutput>> {
+    let mut hooks: HashMap<u8, Vec<WebhookConfig>> = HashMap::new();
+    let mut osc_messages: HashMap<u8, Vec<OscConfig>> = HashMap::new();
+    for (port, outputs) in &config.port_outputs {
+        for output in outputs {
+            match output {
+                OutputConfig::Webhook(hook) => hooks.entry(*port).or_default().push(hook.clone()),
+                OutputConfig::Osc(message) => osc_messages.entry(*port).or_default().push(message.clone()),
+                OutputConfig::Adam(_) => (),
+            }
+        }
+    }
+    let port_names = config
+        .ports
+        .iter()
+        .map(|port| (port.number, port.name.clone()))
+        .collect();
+    let mut outputs: Vec<Box<dyn TriggerOutput>> = Vec::new();
+    if !hooks.is_empty() {
+        outputs.push(Box::new(WebhookOutput::new(hooks, port_names)));
+    }
+    if !osc_messages.is_empty() {
+        outputs.push(Box::new(OscOutput::new(osc_messages)));
+    }
+    if let Some(mqtt) = &config.mqtt {
+        outputs.push(Box::new(MqttOutput::new(mqtt.clone())));
+    }
+    outputs
+}
+
+struct Route {
+    name: String,
+    settings: Option<Value>,
+    ports: HashSet<u8>,
+    sender: Sender<PortEvent>,
+    handle: JoinHandle<()>,
+}
+///The running outputs and the ports each wants events from. They can be swapped out while running
+#[derive(Default)]
+pub struct Routes {
+    routes: Mutex<Vec<Route>>,
+}
+impl Routes {
+    ///Starts every output that is new or whose settings changed on its own thread and stops the ones they replace.
+    ///Outputs whose settings haven't changed keep running. Returns once the old outputs have finished.
+    pub fn replace(&self, outputs: Vec<Box<dyn TriggerOutput>>) {
+        let mut routes = self.routes.lock().unwrap();
+        let mut old = std::mem::take(&mut *routes);
+        for output in outputs {
+            let (name, settings) = (output.name(), output.settings());
+            let unchanged = old
+                .iter()
+                .position(|route| route.name == name && settings.is_some() && route.settings == settings);
+            if let Some(index) = unchanged {
+                info!("The {:} output hasn't changed, it keeps running", name);
+                routes.push(old.remove(index));
+                continue;
+            }
+            let ports = output.ports();
+            info!("Starting {:} output for ports {:?}", name, ports);
+            let (sender, receiver) = channel();
+            let handle = thread::spawn(move || output.run(receiver));
+            routes.push(Route {
+                name,
+                settings,
+                ports,
+                sender,
+                handle,
+            });
+        }
+        drop(routes);
+        for Route { name, sender, handle, .. } in old {
+            drop(sender);
+            if let Err(e) = handle.join() {
+                error!("The {:} output failed with: {:?}", name, e);
+            }
+        }
+    }
+    ///The thread of each running output by name, to tell which were restarted
+    #[cfg(test)]
+    pub fn threads(&self) -> HashMap<String, thread::ThreadId> {
+        self.routes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|route| (route.name.clone(), route.handle.thread().id()))
+            .collect()
+    }
+    ///Forwards the event to each output that wants it
+    fn send(&self, event: &PortEvent) {
+        for route in self.routes.lock().unwrap().iter() {
+            if route.ports.contains(&event.logical_port) && route.sender.send(event.clone()).is_err() {
+                error!("The {:} output has stopped, {:?} was not sent to it", route.name, event);
+            }
+        }
+    }
+}
+
+///Forwards each event to the outputs that want it.
+///Returns once `events` closes and the outputs have finished.
+pub fn start(events: Receiver<PortEvent>, routes: Arc<Routes>) {
+    for event in events.iter() {
+        routes.send(&event);
+    }
+    routes.replace(Vec::new());
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vdcp::types::EventKind;
+    use serde_json::json;
+
+    ///Passes on every event it gets so the test can see it
+    struct Recorder {
+        ports: HashSet<u8>,
+        settings: Option<Value>,
+        seen: Sender<PortEvent>,
+    }
+    impl TriggerOutput for Recorder {
+        fn name(&self) -> String {
+            "recorder".to_string()
+        }
+        fn ports(&self) -> HashSet<u8> {
+            self.ports.clone()
+        }
+        fn settings(&self) -> Option<Value> {
+            self.settings.clone()
+        }
+        fn run(self: Box<Self>, events: Receiver<PortEvent>) {
+            for event in events.iter() {
+                self.seen.send(event).unwrap();
+            }
+        }
+    }
+    fn event(kind: EventKind, port: u8) -> PortEvent {
+        PortEvent::new(kind, port, "clip")
+    }
+    fn recorder(ports: &[u8]) -> (Box<dyn TriggerOutput>, Receiver<PortEvent>) {
+        recorder_with(ports, None)
+    }
+    fn recorder_with(ports: &[u8], settings: Option<Value>) -> (Box<dyn TriggerOutput>, Receiver<PortEvent>) {
+        let (seen, received) = channel();
+        let output = Recorder {
+            ports: ports.iter().copied().collect(),
+            settings,
+            seen,
+        };
+        (Box::new(output), received)
+    }
+
+    #[test]
+    fn events_go_to_outputs_for_their_port() {
+        let (first, first_seen) = recorder(&[1, 2]);
+        let (second, second_seen) = recorder(&[2]);
+        let (sender, events) = channel();
+        sender.send(event(EventKind::Play, 1)).unwrap();
+        sender.send(event(EventKind::Play, 2)).unwrap();
+        sender.send(event(EventKind::Stop, 3)).unwrap();
+        drop(sender);
+        let routes = Arc::new(Routes::default());
+        routes.replace(vec![first, second]);
+        start(events, routes);
+        assert_eq!(
+            first_seen.try_iter().collect::<Vec<_>>(),
+            vec![event(EventKind::Play, 1), event(EventKind::Play, 2)]
+        );
+        assert_eq!(second_seen.try_iter().collect::<Vec<_>>(), vec![event(EventKind::Play, 2)]);
+    }
+    #[test]
+    fn replaced_outputs_stop_getting_events() {
+        let (first, first_seen) = recorder(&[1]);
+        let (second, second_seen) = recorder(&[1]);
+        let routes = Routes::default();
+        routes.replace(vec![first]);
+        routes.send(&event(EventKind::Play, 1));
+        routes.replace(vec![second]);
+        routes.send(&event(EventKind::Stop, 1));
+        //the old output has finished so its channel is closed
+        assert_eq!(first_seen.iter().collect::<Vec<_>>(), vec![event(EventKind::Play, 1)]);
+        assert_eq!(second_seen.try_iter().collect::<Vec<_>>(), vec![event(EventKind::Stop, 1)]);
+    }
+    #[test]
+    fn unchanged_outputs_keep_running() {
+        let (first, first_seen) = recorder_with(&[1], Some(json!({ "target": "a" })));
+        let (same, same_seen) = recorder_with(&[1], Some(json!({ "target": "a" })));
+        let (changed, changed_seen) = recorder_with(&[1], Some(json!({ "target": "b" })));
+        let routes = Routes::default();
+        routes.replace(vec![first]);
+        routes.send(&event(EventKind::Play, 1));
+        routes.replace(vec![same]);
+        routes.send(&event(EventKind::Still, 1));
+        routes.replace(vec![changed]);
+        routes.send(&event(EventKind::Stop, 1));
+        //the output that would have replaced the first was never started
+        assert!(same_seen.try_iter().next().is_none());
+        assert_eq!(
+            first_seen.iter().collect::<Vec<_>>(),
+            vec![event(EventKind::Play, 1), event(EventKind::Still, 1)]
+        );
+        assert_eq!(changed_seen.try_iter().collect::<Vec<_>>(), vec![event(EventKind::Stop, 1)]);
+    }
+    #[test]
+    fn adam_mapping_merges_both_config_sections() {
+        let mut config = Config::default();
+        config.adam_output_mapping.insert(1, AdamCommand::new(0, 0));
+        config.port_outputs.insert(
+            1,
+            vec![OutputConfig::Adam(AdamCommand::new(0, 1))],
+        );
+        config.port_outputs.insert(2, vec![OutputConfig::Adam(AdamCommand::new(1, 0))]);
+        let mapping = adam_mapping(&config);
+        assert_eq!(mapping[&1].len(), 2);
+        assert_eq!(mapping[&2].len(), 1);
+    }
+}
@@ -0,0 +1,444 @@
+//===MQTT event publishing===
+//Publishes every port event to an MQTT broker so dashboards can follow what the automation is doing.
+//Only what is needed to publish is implemented: connect, publish at QoS 0, ping and disconnect.
+//
+//Topics, under `<topic_prefix>/<device>`:
+//`status` online or offline, retained. Set to offline by the broker if we drop off.
+//`port/<number>/event` a json object for every event
+//`port/<number>/state` idle, cued or playing, retained
+//The number is the port in the config, whichever logical port the automation selected, so each port keeps its topics.
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use super::TriggerOutput;
+use crate::events::unix_time_ms;
+use crate::adam::Secret;
+use crate::vdcp::types::{EventKind, PortEvent};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+///How long to wait before trying to connect again after failing
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+fn default_topic_prefix() -> String {
+    "vdcp".to_string()
+}
+fn default_device() -> String {
+    "vdcp-spoof".to_string()
+}
+fn default_keep_alive_s() -> u16 {
+    30
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MqttConfig {
+    ///The broker as `host:port`
+    pub broker: String,
+    ///Defaults to the device name
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    ///Identifies this spoof in the topics when several share a broker
+    #[serde(default = "default_device")]
+    pub device: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<Secret>,
+    #[serde(default = "default_keep_alive_s")]
+    pub keep_alive_s: u16,
+}
+impl MqttConfig {
+    fn topic(&self, path: &str) -> String {
+        format!("{:}/{:}/{:}", self.topic_prefix, self.device, path)
+    }
+}
+
+///The json published for each event
+#[derive(Debug, Serialize)]
+struct EventMessage<'a> {
+    event: EventKind,
+    port: u8,
+    logical_port: u8,
+    clip: &'a str,
+    detail: &'a Option<String>,
+    timestamp_ms: u64,
+}
+
+///What a port is doing, as published on its retained state topic
+fn port_state(kind: EventKind) -> Option<&'static str> {
+    match kind {
+        EventKind::Play => Some("playing"),
+        EventKind::Cue => Some("cued"),
+        EventKind::Still => Some("still"),
+        EventKind::Stop => Some("idle"),
+        _ => None,
+    }
+}
+
+///A connection to the broker
+struct MqttClient {
+    stream: TcpStream,
+}
+impl MqttClient {
+    fn connect(config: &MqttConfig) -> io::Result<MqttClient> {
+        let address = config
+            .broker
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "broker did not resolve to an address"))?;
+        let mut stream = TcpStream::connect_timeout(&address, Duration::from_secs(2))?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&connect_packet(config)?)?;
+        let (packet_type, body) = read_packet(&mut stream)?;
+        match (packet_type, body.get(1)) {
+            (CONNACK, Some(0)) => Ok(MqttClient { stream }),
+            (CONNACK, code) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("broker refused the connection with code {:?}", code),
+            )),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "broker did not reply with a CONNACK")),
+        }
+    }
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        self.stream.write_all(&publish_packet(topic, payload, retain))
+    }
+    fn ping(&mut self) -> io::Result<()> {
+        self.stream.write_all(&[PINGREQ, 0])?;
+        match read_packet(&mut self.stream)? {
+            (PINGRESP, _) => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "broker did not reply with a PINGRESP")),
+        }
+    }
+    fn disconnect(mut self) {
+        let _ = self.stream.write_all(&[DISCONNECT, 0]);
+    }
+}
+
+pub struct MqttOutput {
+    config: MqttConfig,
+    ///The last state published for each port, sent again after reconnecting
+    states: BTreeMap<u8, &'static str>,
+}
+impl MqttOutput {
+    pub fn new(config: MqttConfig) -> MqttOutput {
+        MqttOutput {
+            config,
+            states: BTreeMap::new(),
+        }
+    }
+    fn connect(&self) -> io::Result<MqttClient> {
+        let mut client = MqttClient::connect(&self.config)?;
+        client.publish(&self.config.topic("status"), b"online", true)?;
+        for (port, state) in &self.states {
+            client.publish(&self.config.topic(&format!("port/{:}/state", port)), state.as_bytes(), true)?;
+        }
+        info!("{{Mqtt}}Connected to {:}", self.config.broker);
+        Ok(client)
+    }
+    fn publish_event(&self, client: &mut MqttClient, event: &PortEvent) -> io::Result<()> {
+        let message = EventMessage {
+            event: event.kind,
+            port: event.port,
+            logical_port: event.logical_port,
+            clip: &event.clip,
+            detail: &event.detail,
+            timestamp_ms: unix_time_ms(event.time),
+        };
+        let payload = serde_json::to_vec(&message).unwrap_or_default();
+        client.publish(&self.config.topic(&format!("port/{:}/event", event.port)), &payload, false)?;
+        if let Some(state) = port_state(event.kind) {
+            client.publish(&self.config.topic(&format!("port/{:}/state", event.port)), state.as_bytes(), true)?;
+        }
+        Ok(())
+    }
+}
+impl TriggerOutput for MqttOutput {
+    fn name(&self) -> String {
+        "mqtt".to_string()
+    }
+    //every event is published, whatever logical port it was for
+    fn ports(&self) -> HashSet<u8> {
+        (0..=u8::MAX).collect()
+    }
+    fn settings(&self) -> Option<Value> {
+        Some(json!({ "config": self.config }))
+    }
+    fn run(mut self: Box<Self>, events: Receiver<PortEvent>) {
+        let keep_alive = Duration::from_secs(self.config.keep_alive_s.max(2) as u64);
+        let mut client: Option<MqttClient> = None;
+        let mut last_attempt: Option<Instant> = None;
+        let mut last_sent = Instant::now();
+        loop {
+            let event = events.recv_timeout(keep_alive / 2);
+            if let Err(RecvTimeoutError::Disconnected) = event {
+                break;
+            }
+            //connecting republishes the states saved so far, so the event's own state is saved afterwards
+            //to not publish it twice
+            if client.is_none() && last_attempt.map_or(true, |at| at.elapsed() >= RECONNECT_DELAY) {
+                last_attempt = Some(Instant::now());
+                match self.connect() {
+                    Ok(connected) => client = Some(connected),
+                    Err(e) => warn!("{{Mqtt}}Could not connect to {:}: {:}", self.config.broker, e),
+                }
+            }
+            //kept even while disconnected so the broker is up to date once we reconnect
+            if let Ok(event) = &event {
+                if let Some(state) = port_state(event.kind) {
+                    self.states.insert(event.port, state);
+                }
+            }
+            let connected = match client.as_mut() {
+                Some(connected) => connected,
+                None => continue,
+            };
+            let result = match &event {
+                Ok(event) => self.publish_event(connected, event),
+                Err(_) if last_sent.elapsed() >= keep_alive / 2 => connected.ping(),
+                Err(_) => Ok(()),
+            };
+            match result {
+                Ok(()) => last_sent = Instant::now(),
+                Err(e) => {
+                    warn!("{{Mqtt}}Lost connection to {:}: {:}", self.config.broker, e);
+                    client = None;
+                }
+            }
+        }
+        if let Some(client) = client {
+            client.disconnect();
+        }
+    }
+}
+
+///Encodes the remaining length of a packet, 7 bits per byte with the top bit set on all but the last
+fn remaining_length(mut length: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+        if length == 0 {
+            return bytes;
+        }
+    }
+}
+fn mqtt_string(value: &[u8]) -> Vec<u8> {
+    let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(value);
+    bytes
+}
+fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![header];
+    packet.append(&mut remaining_length(body.len()));
+    packet.extend(body);
+    packet
+}
+///A clean session connect with a retained "offline" will on the status topic
+fn connect_packet(config: &MqttConfig) -> io::Result<Vec<u8>> {
+    let password = match &config.password {
+        Some(secret) => Some(secret.resolve().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?),
+        None => None,
+    };
+    //clean session, will flag and will retain
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = mqtt_string(b"MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&config.keep_alive_s.to_be_bytes());
+    let client_id = config.client_id.as_ref().unwrap_or(&config.device);
+    body.append(&mut mqtt_string(client_id.as_bytes()));
+    body.append(&mut mqtt_string(config.topic("status").as_bytes()));
+    body.append(&mut mqtt_string(b"offline"));
+    if let Some(username) = &config.username {
+        body.append(&mut mqtt_string(username.as_bytes()));
+    }
+    if let Some(password) = password {
+        body.append(&mut mqtt_string(password.as_bytes()));
+    }
+    Ok(packet(CONNECT, body))
+}
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = mqtt_string(topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(PUBLISH | retain as u8, body)
+}
+///Reads a whole packet, returning its type and everything after the fixed header
+fn read_packet(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 1];
+    stream.read_exact(&mut header)?;
+    let mut length = 0usize;
+    for shift in 0..4 {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        length |= ((byte[0] & 0x7F) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body)?;
+    Ok((header[0], body))
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    fn config(broker: &str) -> MqttConfig {
+        MqttConfig {
+            broker: broker.to_string(),
+            client_id: None,
+            topic_prefix: default_topic_prefix(),
+            device: "studio".to_string(),
+            username: Some("user".to_string()),
+            password: Some(Secret::Plain("pass".to_string())),
+            keep_alive_s: default_keep_alive_s(),
+        }
+    }
+    ///Accepts one client and records what it publishes until it disconnects
+    fn fake_broker() -> (String, thread::JoinHandle<(Vec<u8>, Vec<(String, String, bool)>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (packet_type, connect) = read_packet(&mut stream).unwrap();
+            assert_eq!(packet_type, CONNECT);
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            let mut published = Vec::new();
+            loop {
+                let (packet_type, body) = read_packet(&mut stream).unwrap();
+                match packet_type & 0xF0 {
+                    PUBLISH => {
+                        let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8(body[2..2 + topic_length].to_vec()).unwrap();
+                        let payload = String::from_utf8(body[2 + topic_length..].to_vec()).unwrap();
+                        published.push((topic, payload, packet_type & 0x01 == 1));
+                    }
+                    DISCONNECT => return (connect, published),
+                    other => panic!("unexpected packet {:x}", other),
+                }
+            }
+        });
+        (address, broker)
+    }
+
+    #[test]
+    fn remaining_length_encoding() {
+        assert_eq!(remaining_length(0), vec![0]);
+        assert_eq!(remaining_length(127), vec![127]);
+        assert_eq!(remaining_length(128), vec![0x80, 0x01]);
+        assert_eq!(remaining_length(16_383), vec![0xFF, 0x7F]);
+        let packet = publish_packet("a/b", &vec![b'x'; 200], true);
+        let (packet_type, body) = read_packet(&mut packet.as_slice()).unwrap();
+        assert_eq!(packet_type, PUBLISH | 1);
+        assert_eq!(body.len(), 205);
+    }
+    #[test]
+    fn connect_packet_has_will_and_credentials() {
+        let packet = connect_packet(&config("localhost:1883")).unwrap();
+        let (packet_type, body) = read_packet(&mut packet.as_slice()).unwrap();
+        assert_eq!(packet_type, CONNECT);
+        assert_eq!(&body[..7], &[0, 4, b'M', b'Q', b'T', b'T', 4]);
+        assert_eq!(body[7], 0x80 | 0x40 | 0x20 | 0x04 | 0x02);
+        let rest = String::from_utf8_lossy(&body[10..]);
+        for part in &["studio", "vdcp/studio/status", "offline", "user", "pass"] {
+            assert!(rest.contains(part), "{:} missing from connect", part);
+        }
+    }
+    #[test]
+    fn publishes_events_and_retained_state() {
+        let (address, broker) = fake_broker();
+        let output = Box::new(MqttOutput::new(config(&address)));
+        let (sender, events) = channel();
+        sender.send(PortEvent::new(EventKind::Cue, 1, "first")).unwrap();
+        sender.send(PortEvent::new(EventKind::Play, 1, "first")).unwrap();
+        sender
+            .send(PortEvent::new(EventKind::Error, 1, "first").with_detail("bad checksum"))
+            .unwrap();
+        drop(sender);
+        output.run(events);
+
+        let (_, published) = broker.join().unwrap();
+        let topics: Vec<_> = published.iter().map(|(topic, _, retain)| (topic.as_str(), *retain)).collect();
+        assert_eq!(
+            topics,
+            vec![
+                ("vdcp/studio/status", true),
+                ("vdcp/studio/port/1/event", false),
+                ("vdcp/studio/port/1/state", true),
+                ("vdcp/studio/port/1/event", false),
+                ("vdcp/studio/port/1/state", true),
+                ("vdcp/studio/port/1/event", false),
+            ]
+        );
+        assert_eq!(published[2].1, "cued");
+        assert_eq!(published[4].1, "playing");
+        let error: serde_json::Value = serde_json::from_str(&published[5].1).unwrap();
+        assert_eq!(error["event"], "error");
+        assert_eq!(error["detail"], "bad checksum");
+    }
+    #[test]
+    fn remapped_port_publishes_under_its_own_topics() {
+        let (address, broker) = fake_broker();
+        let output = Box::new(MqttOutput::new(config(&address)));
+        assert!(output.ports().contains(&7), "events for any logical port are taken");
+        let (sender, events) = channel();
+        let mut play = PortEvent::new(EventKind::Play, 1, "first");
+        play.logical_port = 7;
+        sender.send(play).unwrap();
+        drop(sender);
+        output.run(events);
+
+        let (_, published) = broker.join().unwrap();
+        let topics: Vec<_> = published.iter().map(|(topic, _, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec!["vdcp/studio/status", "vdcp/studio/port/1/event", "vdcp/studio/port/1/state"]
+        );
+        let event: serde_json::Value = serde_json::from_str(&published[1].1).unwrap();
+        assert_eq!((&event["port"], &event["logical_port"]), (&json!(1), &json!(7)));
+    }
+    ///Run with a broker such as mosquitto listening on localhost:1883 and `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn publishes_to_local_broker() {
+        let mut config = config("127.0.0.1:1883");
+        config.username = None;
+        config.password = None;
+        let output = MqttOutput::new(config);
+        let mut client = output.connect().expect("is a broker running on localhost:1883?");
+        client.ping().unwrap();
+        client.publish("vdcp/studio/test", b"hello", false).unwrap();
+        client.disconnect();
+    }
+}
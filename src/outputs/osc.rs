@@ -0,0 +1,197 @@
+//===OSC output===
+//Sends OSC messages over UDP for vision mixers and lighting desks.
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::net::UdpSocket;
+use std::sync::mpsc::Receiver;
+
+use super::TriggerOutput;
+use crate::vdcp::types::{EventKind, PortEvent};
+
+fn default_events() -> Vec<EventKind> {
+    vec![EventKind::Play]
+}
+
+///An argument of an OSC message, either a value from the event or a constant
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OscArg {
+    ///The port number as an int
+    Port,
+    ///The clip id as a string
+    Clip,
+    ///The event type (play, stop, cue...) as a string
+    Event,
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+///An OSC message to send when a port event happens
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OscConfig {
+    ///Where to send the message, as `ip:port`
+    pub target: String,
+    ///The OSC address. `{{port}}` is replaced with the port number
+    pub address: String,
+    #[serde(default)]
+    pub args: Vec<OscArg>,
+    ///The events that send the message
+    #[serde(default = "default_events")]
+    pub events: Vec<EventKind>,
+}
+
+pub struct OscOutput {
+    messages: HashMap<u8, Vec<OscConfig>>,
+}
+impl OscOutput {
+    pub fn new(messages: HashMap<u8, Vec<OscConfig>>) -> OscOutput {
+        OscOutput { messages }
+    }
+    ///The packets to send for an event and where to send them
+    fn packets(&self, event: &PortEvent) -> Vec<(&str, Vec<u8>)> {
+        self.messages
+            .get(&event.logical_port)
+            .into_iter()
+            .flatten()
+            .filter(|message| message.events.contains(&event.kind))
+            .map(|message| (message.target.as_str(), encode(message, event)))
+            .collect()
+    }
+}
+impl TriggerOutput for OscOutput {
+    fn name(&self) -> String {
+        "osc".to_string()
+    }
+    fn ports(&self) -> HashSet<u8> {
+        self.messages.keys().copied().collect()
+    }
+    fn settings(&self) -> Option<Value> {
+        Some(json!({ "messages": self.messages }))
+    }
+    fn run(self: Box<Self>, events: Receiver<PortEvent>) {
+        let socket = match UdpSocket::bind("0.0.0.0:0") {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("{{Osc}}Could not open a udp socket, no OSC will be sent: {:}", e);
+                return;
+            }
+        };
+        for event in events.iter() {
+            for (target, packet) in self.packets(&event) {
+                info!("{{Osc}}Sending {:?} to {:} for {:?}", packet, target, event);
+                if let Err(e) = socket.send_to(&packet, target) {
+                    error!("{{Osc}}Sending to {:} failed: {:}", target, e);
+                }
+            }
+        }
+    }
+}
+
+///Builds the OSC message for an event: the address, the type tags then each argument
+fn encode(message: &OscConfig, event: &PortEvent) -> Vec<u8> {
+    let address = message.address.replace("{{port}}", &event.logical_port.to_string());
+    let mut tags = String::from(",");
+    let mut args = Vec::new();
+    for arg in &message.args {
+        match arg {
+            OscArg::Port => {
+                tags.push('i');
+                args.extend_from_slice(&(event.logical_port as i32).to_be_bytes());
+            }
+            OscArg::Int(value) => {
+                tags.push('i');
+                args.extend_from_slice(&value.to_be_bytes());
+            }
+            OscArg::Float(value) => {
+                tags.push('f');
+                args.extend_from_slice(&value.to_be_bytes());
+            }
+            OscArg::Clip => {
+                tags.push('s');
+                args.append(&mut osc_string(&event.clip));
+            }
+            OscArg::Event => {
+                tags.push('s');
+                args.append(&mut osc_string(event.kind.as_str()));
+            }
+            OscArg::String(value) => {
+                tags.push('s');
+                args.append(&mut osc_string(value));
+            }
+        }
+    }
+    let mut packet = osc_string(&address);
+    packet.append(&mut osc_string(&tags));
+    packet.append(&mut args);
+    packet
+}
+///OSC strings are null terminated and padded with nulls to a multiple of 4 bytes
+fn osc_string(value: &str) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    while bytes.len() % 4 != 0 {
+        bytes.push(0);
+    }
+    bytes
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn message(target: &str, args: Vec<OscArg>) -> OscConfig {
+        OscConfig {
+            target: target.to_string(),
+            address: "/vdcp/{{port}}".to_string(),
+            args,
+            events: vec![EventKind::Play, EventKind::Stop],
+        }
+    }
+
+    #[test]
+    fn strings_are_padded() {
+        assert_eq!(osc_string("abc"), b"abc\0".to_vec());
+        assert_eq!(osc_string("abcd"), b"abcd\0\0\0\0".to_vec());
+        assert_eq!(osc_string(""), b"\0\0\0\0".to_vec());
+    }
+    #[test]
+    fn encode_typed_args() {
+        let message = message(
+            "127.0.0.1:9000",
+            vec![OscArg::Port, OscArg::Clip, OscArg::Event, OscArg::Float(0.5)],
+        );
+        let packet = encode(&message, &PortEvent::new(EventKind::Stop, 2, "first"));
+        let mut expected = b"/vdcp/2\0,issf\0\0\0".to_vec();
+        expected.extend_from_slice(&[0, 0, 0, 2]);
+        expected.extend_from_slice(b"first\0\0\0stop\0\0\0\0");
+        expected.extend_from_slice(&0.5f32.to_be_bytes());
+        assert_eq!(packet, expected);
+    }
+    #[test]
+    fn sends_over_udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let target = receiver.local_addr().unwrap().to_string();
+        let mut messages = HashMap::new();
+        messages.insert(1, vec![message(&target, vec![OscArg::Int(7)])]);
+        let output = Box::new(OscOutput::new(messages));
+
+        let (sender, events) = std::sync::mpsc::channel();
+        sender.send(PortEvent::new(EventKind::Cue, 1, "first")).unwrap();
+        sender.send(PortEvent::new(EventKind::Play, 1, "first")).unwrap();
+        drop(sender);
+        output.run(events);
+
+        let mut buffer = [0u8; 64];
+        let length = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"/vdcp/1\0,i\0\0\0\0\0\x07");
+    }
+}
@@ -0,0 +1,280 @@
+//===HTTP webhook output===
+//Calls a url on port events so systems like graphics engines and encoders can follow the playout.
+use log::{error, info};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::mpsc::Receiver;
+
+use super::TriggerOutput;
+use crate::events::unix_time_ms;
+use crate::vdcp::types::{EventKind, PortEvent};
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+fn default_events() -> Vec<EventKind> {
+    vec![EventKind::Play]
+}
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+///A url to call when a port event happens
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    ///Template for the body. `{{port}}`, `{{port_name}}`, `{{logical_port}}`, `{{clip}}`, `{{event}}` and `{{timestamp_ms}}`
+    ///are replaced with their values, escaped for use inside a json string.
+    ///`{{port}}` and `{{port_name}}` are the port in the config, `{{logical_port}}` the one the automation selected.
+    ///Without a template the body is a json object holding all of them
+    #[serde(default)]
+    pub body: Option<String>,
+    ///The events that call the hook
+    #[serde(default = "default_events")]
+    pub events: Vec<EventKind>,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+///The values a body template can use
+#[derive(Debug, Serialize)]
+struct HookValues<'a> {
+    event: EventKind,
+    port: u8,
+    port_name: &'a str,
+    logical_port: u8,
+    clip: &'a str,
+    timestamp_ms: u64,
+}
+impl HookValues<'_> {
+    fn render(&self, template: &Option<String>) -> String {
+        let template = match template {
+            Some(template) => template,
+            None => return serde_json::to_string(self).unwrap_or_default(),
+        };
+        //serde gives a quoted json string, the quotes are left to the template
+        let escape = |value: &str| {
+            let quoted = serde_json::to_string(value).unwrap_or_default();
+            quoted[1..quoted.len() - 1].to_string()
+        };
+        template
+            .replace("{{port}}", &self.port.to_string())
+            .replace("{{port_name}}", &escape(self.port_name))
+            .replace("{{logical_port}}", &self.logical_port.to_string())
+            .replace("{{clip}}", &escape(self.clip))
+            .replace("{{event}}", self.event.as_str())
+            .replace("{{timestamp_ms}}", &self.timestamp_ms.to_string())
+    }
+}
+
+///A single call to make
+#[derive(Debug)]
+struct HookRequest {
+    hook: WebhookConfig,
+    body: String,
+}
+
+pub struct WebhookOutput {
+    hooks: HashMap<u8, Vec<WebhookConfig>>,
+    port_names: HashMap<u8, String>,
+}
+impl WebhookOutput {
+    pub fn new(hooks: HashMap<u8, Vec<WebhookConfig>>, port_names: HashMap<u8, String>) -> WebhookOutput {
+        WebhookOutput { hooks, port_names }
+    }
+    fn requests(&self, event: &PortEvent) -> Vec<HookRequest> {
+        let values = HookValues {
+            event: event.kind,
+            port: event.port,
+            port_name: self.port_names.get(&event.port).map(String::as_str).unwrap_or(""),
+            logical_port: event.logical_port,
+            clip: &event.clip,
+            timestamp_ms: unix_time_ms(event.time),
+        };
+        self.hooks
+            .get(&event.logical_port)
+            .into_iter()
+            .flatten()
+            .filter(|hook| hook.events.contains(&event.kind))
+            .map(|hook| HookRequest {
+                hook: hook.clone(),
+                body: values.render(&hook.body),
+            })
+            .collect()
+    }
+}
+impl TriggerOutput for WebhookOutput {
+    fn name(&self) -> String {
+        "webhook".to_string()
+    }
+    fn ports(&self) -> HashSet<u8> {
+        self.hooks.keys().copied().collect()
+    }
+    fn settings(&self) -> Option<Value> {
+        Some(json!({ "hooks": self.hooks, "port_names": self.port_names }))
+    }
+    fn run(self: Box<Self>, events: Receiver<PortEvent>) {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(5)
+            .build()
+            .expect("Webhook thread pool failed to be created");
+        for event in events.iter() {
+            let requests = self.requests(&event);
+            thread_pool.spawn(move || dispatch_hook_requests(requests));
+        }
+    }
+}
+
+fn dispatch_hook_requests(requests: Vec<HookRequest>) {
+    requests.into_par_iter().for_each(|request| {
+        if let Err(e) = send(&request) {
+            error!("{{Webhook}}{:}", e);
+        }
+    });
+}
+fn send(request: &HookRequest) -> Result<(), String> {
+    let hook = &request.hook;
+    let mut call = ureq::request(&hook.method, &hook.url);
+    call.timeout_connect(hook.timeout_ms).timeout_read(hook.timeout_ms);
+    if !hook.headers.keys().any(|name| name.eq_ignore_ascii_case("content-type")) {
+        call.set("Content-Type", "application/json");
+    }
+    for (name, value) in &hook.headers {
+        call.set(name, value);
+    }
+    info!("{{Webhook}}Calling {:} {:} | {:}", hook.method, hook.url, request.body);
+    let response = call.send_string(&request.body);
+    match response.ok() {
+        true => Ok(()),
+        false => Err(format!(
+            "Calling {:} {:} failed response: {:?}",
+            hook.method, hook.url, response
+        )),
+    }
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    fn hook(url: &str, body: Option<&str>) -> WebhookConfig {
+        WebhookConfig {
+            method: default_method(),
+            url: url.to_string(),
+            headers: BTreeMap::new(),
+            body: body.map(str::to_string),
+            events: vec![EventKind::Play, EventKind::Cue],
+            timeout_ms: default_timeout_ms(),
+        }
+    }
+    fn output(hooks: Vec<WebhookConfig>) -> WebhookOutput {
+        let mut by_port = HashMap::new();
+        by_port.insert(1, hooks);
+        let mut names = HashMap::new();
+        names.insert(1, "studio \"a\"".to_string());
+        WebhookOutput::new(by_port, names)
+    }
+
+    #[test]
+    fn template_is_filled_and_escaped() {
+        let output = output(vec![hook(
+            "http://localhost/",
+            Some(r#"{"port": {{port}}, "name": "{{port_name}}", "clip": "{{clip}}", "event": "{{event}}", "at": {{timestamp_ms}}}"#),
+        )]);
+        let mut event = PortEvent::new(EventKind::Cue, 1, "first");
+        event.time = SystemTime::UNIX_EPOCH + Duration::from_millis(1234);
+        let requests = output.requests(&event);
+        assert_eq!(
+            requests[0].body,
+            r#"{"port": 1, "name": "studio \"a\"", "clip": "first", "event": "cue", "at": 1234}"#
+        );
+    }
+    #[test]
+    fn remapped_port_is_named_by_its_config() {
+        let mut by_port = HashMap::new();
+        by_port.insert(
+            3,
+            vec![
+                hook("http://localhost/", Some("{{port}} {{port_name}} {{logical_port}}")),
+                hook("http://localhost/", None),
+            ],
+        );
+        let mut names = HashMap::new();
+        names.insert(1, "studio a".to_string());
+        names.insert(3, "studio c".to_string());
+        let output = WebhookOutput::new(by_port, names);
+        let mut event = PortEvent::new(EventKind::Play, 1, "first");
+        event.logical_port = 3;
+        let requests = output.requests(&event);
+        assert_eq!(requests[0].body, "1 studio a 3");
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!((&body["port"], &body["port_name"], &body["logical_port"]), (&json!(1), &json!("studio a"), &json!(3)));
+    }
+    #[test]
+    fn default_body_and_event_filter() {
+        let output = output(vec![hook("http://localhost/", None)]);
+        let requests = output.requests(&PortEvent::new(EventKind::Play, 1, "first"));
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["event"], "play");
+        assert_eq!(body["clip"], "first");
+        assert_eq!(body["port_name"], "studio \"a\"");
+        assert!(output.requests(&PortEvent::new(EventKind::Stop, 1, "first")).is_empty());
+        assert!(output.requests(&PortEvent::new(EventKind::Play, 2, "first")).is_empty());
+    }
+    #[test]
+    fn hook_is_called() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{:}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = Vec::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                head.push(line.trim().to_string());
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = stream;
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        let mut hook = hook(&url, Some("{{clip}}"));
+        hook.method = "PUT".to_string();
+        hook.headers.insert("X-Source".to_string(), "vdcp".to_string());
+        let request = HookRequest {
+            hook,
+            body: "first".to_string(),
+        };
+        send(&request).unwrap();
+        let (head, body) = server.join().unwrap();
+        assert_eq!(head[0], "PUT /hook HTTP/1.1");
+        assert!(head.iter().any(|line| line == "X-Source: vdcp"));
+        assert!(head.iter().any(|line| line == "Content-Type: application/json"));
+        assert_eq!(body, "first");
+    }
+}
@@ -0,0 +1,634 @@
+//===Runtime===
+//Owns the port threads and trigger outputs so the config can be changed without restarting.
+//Only the ports whose settings changed are restarted, the others keep running untouched.
+//The trigger outputs are only restarted when their own settings changed, the adam state is carried over when they are.
+//The config file is watched so changes made to it by hand are applied the same way.
+//On SIGINT or SIGTERM the ports are stopped first, then the outputs finish what they were sending.
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::adam::{self, dispatch::DispatchStats, health::AdamHealth, mock::MockAdam, outcomes::TriggerLog};
+use crate::adam::{AdamCarryOver, AdamModule, AdamModules, AdamProtocol, Scheme, Secret};
+use crate::config::{validate, Config, ConfigDiff, VDCPPort};
+use crate::events::EventBus;
+use crate::outputs::{self, Routes, TriggerOutput};
+use crate::serial::{self, PortActivities, SerialConnections};
+use crate::vdcp::types::{ClipStatus, PortAlarmMap, PortAlarms, PortConfig, PortStatus};
+
+///A running port thread. Dropping `times` stops it
+struct PortHandle {
+    times: SyncSender<Vec<u16>>,
+    thread: JoinHandle<()>,
+}
+
+///The mock standing in for an adam module, and the credentials it was started with
+struct SimulatedAdam {
+    mock: MockAdam,
+    username: String,
+    password: String,
+}
+
+pub struct Runtime {
+    config_path: PathBuf,
+    ///With `--simulate-adam` every adam module is swapped for a local mock, kept for as long as the program runs
+    simulated_adams: Option<Mutex<BTreeMap<u8, SimulatedAdam>>>,
+    config: Mutex<Config>,
+    ///When the config file was last loaded or written by us, so our own writes aren't reloaded
+    config_modified: Mutex<Option<SystemTime>>,
+    ports: Mutex<BTreeMap<u8, PortHandle>>,
+    ///Set once shutting down, after which the config can't be changed
+    stopped: AtomicBool,
+    ///Each port's alarms are raised by the adam thread and reported in the port's vdcp status
+    alarms: Mutex<PortAlarmMap>,
+    event_bus: EventBus,
+    routes: Arc<Routes>,
+    pub trigger_log: Arc<TriggerLog>,
+    pub adam_health: Arc<AdamHealth>,
+    pub adam_queues: Arc<DispatchStats>,
+    ///Carries toggles and scheduled commands over when the adam output is rebuilt
+    adam_state: Arc<AdamCarryOver>,
+    ///Whether each port's serial device is open
+    pub connections: Arc<SerialConnections>,
+    ///What each port is doing as far as the automation is concerned
+    pub activities: Arc<PortActivities>,
+}
+
+impl Runtime {
+    ///Starts a thread for every port and every trigger output
+    pub fn start(config: Config, config_path: PathBuf, simulate_adam: bool, event_bus: EventBus) -> Arc<Runtime> {
+        let routes = Arc::new(Routes::default());
+        let outputs_events = event_bus.subscribe();
+        let router = routes.clone();
+        thread::spawn(move || outputs::start(outputs_events, router));
+
+        let runtime = Arc::new(Runtime {
+            config_path,
+            simulated_adams: if simulate_adam {
+                warn!("--simulate-adam given. Adam requests will go to local mock modules over REST instead of the real hardware");
+                Some(Mutex::new(BTreeMap::new()))
+            } else {
+                None
+            },
+            config: Mutex::new(Config::default()),
+            config_modified: Mutex::new(None),
+            ports: Mutex::new(BTreeMap::new()),
+            stopped: AtomicBool::new(false),
+            alarms: Mutex::new(PortAlarmMap::new()),
+            event_bus,
+            routes,
+            trigger_log: Arc::new(TriggerLog::default()),
+            adam_health: Arc::new(AdamHealth::default()),
+            adam_queues: Arc::new(DispatchStats::default()),
+            adam_state: Arc::new(AdamCarryOver::default()),
+            connections: Arc::new(SerialConnections::default()),
+            activities: Arc::new(PortActivities::default()),
+        });
+        *runtime.config_modified.lock().unwrap() = modified(&runtime.config_path);
+        {
+            let mut current = runtime.config.lock().unwrap();
+            let diff = ConfigDiff::between(&current, &config);
+            runtime.apply(&mut current, config, &diff);
+        }
+        runtime
+    }
+
+    ///A copy of the config that is running
+    pub fn config(&self) -> Config {
+        self.config.lock().unwrap().clone()
+    }
+    pub fn alarms(&self) -> PortAlarmMap {
+        self.alarms.lock().unwrap().clone()
+    }
+    ///The token needed to edit the config, if editing is turned on
+    pub fn api_token(&self) -> Option<String> {
+        let token = self.config.lock().unwrap().api_token.clone()?;
+        match token.resolve() {
+            Ok(token) if !token.is_empty() => Some(token),
+            Ok(_) => None,
+            Err(e) => {
+                error!("api_token can't be loaded, editing the config is turned off: {:}", e);
+                None
+            }
+        }
+    }
+    ///Sends clip times to the port at `index` in the config's port list
+    pub fn send_times(&self, index: usize, times: Vec<u16>) -> Result<(), String> {
+        let number = self
+            .config
+            .lock()
+            .unwrap()
+            .ports
+            .get(index)
+            .map(|port| port.number)
+            .ok_or_else(|| format!("there is no port {:} in the config", index))?;
+        match self.ports.lock().unwrap().get(&number) {
+            Some(port) => port
+                .times
+                .send(times)
+                .map_err(|e| format!("port {:} has stopped: {:}", number, e)),
+            None => Err(format!("port {:} isn't running", number)),
+        }
+    }
+
+    ///Changes the config, applies it and writes it back to the config file, which loses the comments in the file
+    pub fn edit(&self, change: impl FnOnce(&mut Config) -> Result<(), String>) -> Result<ConfigDiff, String> {
+        let mut current = self.config.lock().unwrap();
+        self.refuse_if_stopped()?;
+        let mut new = current.clone();
+        change(&mut new)?;
+        validate::refuse_errors(&validate::check(&new))?;
+        let diff = ConfigDiff::between(&current, &new);
+        confy::store_path(&self.config_path, &new)
+            .map_err(|e| format!("couldn't write the config to {:?}: {:}", self.config_path, e))?;
+        *self.config_modified.lock().unwrap() = modified(&self.config_path);
+        self.apply(&mut current, new, &diff);
+        Ok(diff)
+    }
+    ///Loads the config file again and applies whatever changed.
+    ///A config that can't be read or is invalid is refused and the running one is kept
+    pub fn reload(&self) -> Result<ConfigDiff, String> {
+        let mut current = self.config.lock().unwrap();
+        self.refuse_if_stopped()?;
+        *self.config_modified.lock().unwrap() = modified(&self.config_path);
+        let new = load(&self.config_path)?;
+        let diff = ConfigDiff::between(&current, &new);
+        self.apply(&mut current, new, &diff);
+        Ok(diff)
+    }
+
+    fn refuse_if_stopped(&self) -> Result<(), String> {
+        if self.stopped.load(Ordering::SeqCst) {
+            return Err("shutting down, the config can't be changed".to_string());
+        }
+        Ok(())
+    }
+
+    ///Stops every port so no more vdcp commands are answered, then stops the outputs once they have
+    ///finished what they were sending and switched off anything they latched on.
+    ///The config is held so an edit that is being written finishes first and no other starts
+    pub fn shutdown(&self) -> Result<(), String> {
+        let _config = self.config.lock().unwrap();
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return Err("already shut down".to_string());
+        }
+        let ports = std::mem::take(&mut *self.ports.lock().unwrap());
+        let mut failed = Vec::new();
+        for (number, port) in ports {
+            info!("[Port:{:}] Stopping", number);
+            if !stop_port(number, port) {
+                failed.push(number);
+            }
+        }
+        info!("Stopping the trigger outputs");
+        self.adam_state.shut_down();
+        self.routes.replace(Vec::new());
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("the threads of ports {:?} panicked", failed))
+        }
+    }
+
+    ///Restarts the ports and outputs the diff says have changed
+    fn apply(&self, current: &mut Config, new: Config, diff: &ConfigDiff) {
+        if diff.is_empty() {
+            return;
+        }
+        info!("Applying config changes:");
+        for change in &diff.changes {
+            info!("    {:}", change);
+        }
+        let new_ports: BTreeMap<_, _> = new.ports.iter().map(|port| (port.number, port)).collect();
+        {
+            let mut ports = self.ports.lock().unwrap();
+            let mut alarms = self.alarms.lock().unwrap();
+            for number in diff.ports_removed.iter().chain(&diff.ports_changed) {
+                if let Some(port) = ports.remove(number) {
+                    info!("[Port:{:}] Stopping", number);
+                    stop_port(*number, port);
+                }
+            }
+            for number in &diff.ports_removed {
+                alarms.remove(number);
+            }
+            for number in diff.ports_added.iter().chain(&diff.ports_changed) {
+                let settings = new_ports[number];
+                let port_alarms = alarms
+                    .entry(*number)
+                    .or_insert_with(|| Arc::new(PortAlarms::default()))
+                    .clone();
+                ports.insert(*number, self.start_port(settings, port_alarms));
+            }
+        }
+        if diff.changes.iter().any(|change| change.starts_with("logging")) {
+            warn!("Logging changes take effect after a restart");
+        }
+        if diff.outputs_changed {
+            info!("Rebuilding the trigger outputs");
+            self.routes.replace(self.build_outputs(&new));
+        }
+        *current = new;
+    }
+
+    fn start_port(&self, settings: &VDCPPort, alarms: Arc<PortAlarms>) -> PortHandle {
+        let (times, receiver) = sync_channel(100);
+        let settings = settings.clone();
+        let bus = self.event_bus.clone();
+        let connections = self.connections.clone();
+        let activities = self.activities.clone();
+        let thread = thread::spawn(move || {
+            info!("spawning port monitoring thread");
+            let config = PortConfig {
+                number: settings.number,
+                configured_number: settings.number,
+                port_status: PortStatus::Idle,
+                clip_status: ClipStatus::Clips,
+                cued_number: 0,
+                clips: settings.segments.iter().map(|a| a.clone().into_bytes()).collect(),
+                events: bus,
+                alarms,
+                clip_ends: None,
+                clip_left: None,
+            };
+            serial::start(settings.port.clone(), receiver, config, connections, activities);
+        });
+        PortHandle { times, thread }
+    }
+
+    ///Builds every trigger output, including adam
+    fn build_outputs(&self, config: &Config) -> Vec<Box<dyn TriggerOutput>> {
+        let mut trigger_outputs = outputs::from_config(config);
+        trigger_outputs.push(Box::new(adam::AdamOutput {
+            port_mapping: outputs::adam_mapping(config),
+            modules: self.simulate_adams(&config.adam_modules),
+            alarms: self.alarms(),
+            log: self.trigger_log.clone(),
+            health: self.adam_health.clone(),
+            tally_mapping: config.adam_tally_mapping.clone(),
+            coalesce_ms: config.adam_coalesce_ms,
+            queues: self.adam_queues.clone(),
+            carry_over: self.adam_state.clone(),
+        }));
+        trigger_outputs
+    }
+
+    ///Points every adam module at its local mock when simulating, so no hardware is touched.
+    ///A module's mock is started the first time the module is seen and kept, so its address, outputs and history
+    ///stay the same when the outputs are rebuilt. The mocks only speak REST so every module is switched to the REST api
+    fn simulate_adams(&self, modules: &AdamModules) -> AdamModules {
+        let mut simulated = match &self.simulated_adams {
+            Some(simulated) => simulated.lock().unwrap(),
+            None => return modules.clone(),
+        };
+        modules
+            .iter()
+            .map(|(id, module)| {
+                let SimulatedAdam { mock, username, password } = simulated.entry(*id).or_insert_with(|| {
+                    let password = module.password.resolve().unwrap_or_default();
+                    let mock = MockAdam::start(
+                        &format!("{:}({:})", id, module.host),
+                        "127.0.0.1:0".parse().unwrap(),
+                        &module.username,
+                        &password,
+                    )
+                    .expect("Failed starting mock adam module");
+                    SimulatedAdam {
+                        mock,
+                        username: module.username.clone(),
+                        password,
+                    }
+                });
+                let simulated = AdamModule {
+                    host: "127.0.0.1".to_string(),
+                    port: Some(mock.address.port()),
+                    scheme: Scheme::Http,
+                    username: username.clone(),
+                    password: Secret::Plain(password.clone()),
+                    protocol: AdamProtocol::Rest,
+                    ..module.clone()
+                };
+                (*id, simulated)
+            })
+            .collect()
+    }
+}
+
+///Reloads the config whenever its file changes
+pub fn watch_config(runtime: Arc<Runtime>) {
+    thread::spawn(move || loop {
+        thread::sleep(WATCH_INTERVAL);
+        let modified = modified(&runtime.config_path);
+        if modified.is_none() || modified == *runtime.config_modified.lock().unwrap() {
+            continue;
+        }
+        //editors often write a file in several goes, give them a moment to finish
+        thread::sleep(WATCH_INTERVAL / 4);
+        info!("Config file {:?} changed, reloading it", runtime.config_path);
+        match runtime.reload() {
+            Ok(diff) if diff.is_empty() => info!("The config file has no changes to apply"),
+            Ok(_) => (),
+            Err(e) => error!("Not applying the changed config file, the running config is kept: {:}", e),
+        }
+    });
+}
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+///Shuts down on SIGINT or SIGTERM and exits with 0 if everything stopped cleanly or 1 if not.
+///A second signal exits straight away with 130
+pub fn stop_on_signal(runtime: Arc<Runtime>) -> Result<(), String> {
+    let signalled = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if signalled.swap(true, Ordering::SeqCst) {
+            warn!("Signalled again, exiting without finishing the shutdown");
+            log::logger().flush();
+            std::process::exit(130);
+        }
+        info!("Signal received, shutting down");
+        //the shutdown runs on its own thread so a second signal can still be handled
+        let runtime = runtime.clone();
+        thread::spawn(move || exit(shutdown_within(&runtime, SHUTDOWN_TIMEOUT)));
+    })
+    .map_err(|e| format!("couldn't handle signals: {:}", e))
+}
+///Long enough for the adam modules to finish their retries and pulses
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+///Shuts the runtime down, giving up if it takes longer than `timeout`
+pub fn shutdown_within(runtime: &Arc<Runtime>, timeout: Duration) -> Result<(), String> {
+    let (done, finished) = channel();
+    let runtime = runtime.clone();
+    thread::spawn(move || {
+        let _ = done.send(runtime.shutdown());
+    });
+    finished
+        .recv_timeout(timeout)
+        .unwrap_or_else(|_| Err(format!("the shutdown didn't finish within {:?}", timeout)))
+}
+///Logs how the shutdown went, flushes the logs and exits with 0 if it went cleanly or 1 if not
+pub fn exit(shutdown: Result<(), String>) -> ! {
+    let code = match shutdown {
+        Ok(()) => {
+            info!("Shut down cleanly");
+            0
+        }
+        Err(e) => {
+            error!("Shut down with errors: {:}", e);
+            1
+        }
+    };
+    log::logger().flush();
+    std::process::exit(code)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+///Reads and checks the config file. Unlike `confy::load_path` a missing file isn't created
+fn load(path: &Path) -> Result<Config, String> {
+    if !path.exists() {
+        return Err(format!("{:?} doesn't exist", path));
+    }
+    let (config, problems) = validate::check_file(path);
+    validate::refuse_errors(&problems)?;
+    config.ok_or_else(|| format!("couldn't read {:?}", path))
+}
+
+///Waits for the port thread to finish so its serial port is free to be opened again. False if it panicked
+fn stop_port(number: u8, port: PortHandle) -> bool {
+    let PortHandle { times, thread } = port;
+    drop(times);
+    let stopped = thread.join().is_ok();
+    if !stopped {
+        warn!("[Port:{:}] thread panicked while stopping", number);
+    }
+    stopped
+}
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(number: u8) -> VDCPPort {
+        VDCPPort {
+            port: "/dev/does-not-exist".to_string(),
+            number,
+            name: format!("port {:}", number),
+            segments: vec!["first".to_string()],
+        }
+    }
+
+    #[test]
+    fn edits_are_applied_and_saved() {
+        let path = std::env::temp_dir().join(format!("vdcp-spoof-runtime-{:}.yaml", std::process::id()));
+        let mut config = Config::default();
+        config.ports = vec![port(1)];
+        let runtime = Runtime::start(config, path.clone(), false, EventBus::new());
+        let first_alarms = runtime.alarms()[&1].clone();
+
+        let diff = runtime
+            .edit(|config| {
+                config.ports.push(port(2));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(diff.ports_added, vec![2]);
+        //the untouched port keeps its state
+        assert!(Arc::ptr_eq(&runtime.alarms()[&1], &first_alarms));
+        assert_eq!(runtime.alarms().len(), 2);
+        let saved: Config = confy::load_path(&path).unwrap();
+        assert_eq!(saved.ports, vec![port(1), port(2)]);
+
+        let refused = runtime.edit(|config| {
+            config.ports.push(port(1));
+            Ok(())
+        });
+        assert!(refused.is_err());
+        assert_eq!(runtime.config().ports.len(), 2);
+
+        runtime
+            .edit(|config| {
+                config.ports.retain(|port| port.number != 1);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(runtime.alarms().keys().collect::<Vec<_>>(), vec![&2]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn editing_only_a_password_logs_in_with_it() {
+        let path = std::env::temp_dir().join(format!("vdcp-spoof-password-{:}.yaml", std::process::id()));
+        let mut config = Config::default();
+        config.adam_modules.insert(
+            0,
+            AdamModule {
+                host: "10.0.0.1".to_string(),
+                password: crate::adam::Secret::Plain("old".to_string()),
+                health_poll_ms: 0,
+                ..Default::default()
+            },
+        );
+        let runtime = Runtime::start(config, path.clone(), false, EventBus::new());
+        let adam = runtime.routes.threads()["adam"];
+
+        let diff = runtime
+            .edit(|config| {
+                config.adam_modules.get_mut(&0).unwrap().password = crate::adam::Secret::Plain("new".to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(diff.changes, vec!["adam_modules.0.password: (hidden) changed".to_string()]);
+        assert_ne!(runtime.routes.threads()["adam"], adam, "the adam output is rebuilt with the new password");
+        assert_eq!(runtime.config().adam_modules[&0].password.resolve(), Ok("new".to_string()));
+        let saved: Config = confy::load_path(&path).unwrap();
+        assert_eq!(saved.adam_modules[&0].password.resolve(), Ok("new".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reload_applies_the_file_and_keeps_bad_files_out() {
+        let path = std::env::temp_dir().join(format!("vdcp-spoof-reload-{:}.yaml", std::process::id()));
+        let mut config = Config::default();
+        config.ports = vec![port(1)];
+        confy::store_path(&path, &config).unwrap();
+        let runtime = Runtime::start(config.clone(), path.clone(), false, EventBus::new());
+
+        config.ports[0].segments.push("second".to_string());
+        confy::store_path(&path, &config).unwrap();
+        let diff = runtime.reload().unwrap();
+        assert_eq!(diff.ports_changed, vec![1]);
+        assert_eq!(diff.changes, vec![r#"ports.1.segments: ["first"] -> ["first","second"]"#.to_string()]);
+        assert!(runtime.reload().unwrap().is_empty());
+
+        std::fs::write(&path, "ports: [ {number: ").unwrap();
+        assert!(runtime.reload().is_err());
+        assert_eq!(runtime.config().ports, config.ports);
+        std::fs::remove_file(&path).unwrap();
+        assert!(runtime.reload().is_err(), "a missing file shouldn't be recreated");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn reload_picks_up_a_rotated_password() {
+        let path = std::env::temp_dir().join(format!("vdcp-spoof-rotate-{:}.yaml", std::process::id()));
+        let mut config = Config::default();
+        config.api_token = Some(crate::adam::Secret::Plain("old".to_string()));
+        confy::store_path(&path, &config).unwrap();
+        let runtime = Runtime::start(config.clone(), path.clone(), false, EventBus::new());
+
+        config.api_token = Some(crate::adam::Secret::Plain("new".to_string()));
+        confy::store_path(&path, &config).unwrap();
+        let diff = runtime.reload().unwrap();
+        assert_eq!(diff.changes, vec!["api_token: (hidden) changed".to_string()]);
+        assert_eq!(runtime.api_token(), Some("new".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reload_only_restarts_the_outputs_that_changed() {
+        let path = std::env::temp_dir().join(format!("vdcp-spoof-outputs-{:}.yaml", std::process::id()));
+        let osc = |target: &str| {
+            outputs::OutputConfig::Osc(outputs::osc::OscConfig {
+                target: target.to_string(),
+                address: "/play".to_string(),
+                args: Vec::new(),
+                events: vec![crate::vdcp::types::EventKind::Play],
+            })
+        };
+        let mut config = Config::default();
+        config.ports = vec![port(1)];
+        config.port_outputs.insert(1, vec![osc("127.0.0.1:9000")]);
+        confy::store_path(&path, &config).unwrap();
+        let runtime = Runtime::start(config.clone(), path.clone(), false, EventBus::new());
+        let before = runtime.routes.threads();
+
+        config.port_outputs.insert(1, vec![osc("127.0.0.1:9001")]);
+        confy::store_path(&path, &config).unwrap();
+        assert!(runtime.reload().unwrap().outputs_changed);
+        let after = runtime.routes.threads();
+        assert_eq!(after["adam"], before["adam"], "the adam settings haven't changed so it keeps running");
+        assert_ne!(after["osc"], before["osc"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn simulated_adams_are_started_once() {
+        let path = std::env::temp_dir().join(format!("vdcp-spoof-simulated-{:}.yaml", std::process::id()));
+        let mut config = Config::default();
+        config.ports = vec![port(1)];
+        let module = AdamModule {
+            host: "10.0.0.1".to_string(),
+            health_poll_ms: 0,
+            ..Default::default()
+        };
+        config.adam_modules.insert(0, module.clone());
+        let runtime = Runtime::start(config, path.clone(), true, EventBus::new());
+        let first = runtime.simulate_adams(&runtime.config().adam_modules);
+        let adam = runtime.routes.threads()["adam"];
+
+        runtime
+            .edit(|config| {
+                let osc = outputs::osc::OscConfig {
+                    target: "127.0.0.1:9000".to_string(),
+                    address: "/play".to_string(),
+                    args: Vec::new(),
+                    events: vec![crate::vdcp::types::EventKind::Play],
+                };
+                config.port_outputs.insert(1, vec![outputs::OutputConfig::Osc(osc)]);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(runtime.routes.threads()["adam"], adam, "the simulated modules haven't changed");
+
+        runtime
+            .edit(|config| {
+                config.adam_modules.insert(1, module);
+                Ok(())
+            })
+            .unwrap();
+        let second = runtime.simulate_adams(&runtime.config().adam_modules);
+        assert_eq!(second[&0].port, first[&0].port, "module 0 keeps its mock");
+        assert_ne!(second[&1].port, first[&0].port);
+        assert_eq!(runtime.simulated_adams.as_ref().unwrap().lock().unwrap().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_stops_the_ports_and_refuses_changes() {
+        let path = std::env::temp_dir().join(format!("vdcp-spoof-shutdown-{:}.yaml", std::process::id()));
+        let mut config = Config::default();
+        config.ports = vec![port(1), port(2)];
+        let runtime = Runtime::start(config, path.clone(), false, EventBus::new());
+        shutdown_within(&runtime, Duration::from_secs(5)).unwrap();
+        assert!(runtime.ports.lock().unwrap().is_empty());
+        assert!(runtime.send_times(0, vec![1]).is_err(), "a stopped port takes no more commands");
+        let refused = runtime.edit(|config| {
+            config.ports.push(port(3));
+            Ok(())
+        });
+        assert!(refused.is_err());
+        assert!(!path.exists());
+        assert!(runtime.shutdown().is_err());
+    }
+
+    #[test]
+    fn editing_needs_a_token() {
+        let path = std::env::temp_dir().join(format!("vdcp-spoof-token-{:}.yaml", std::process::id()));
+        let runtime = Runtime::start(Config::default(), path, false, EventBus::new());
+        assert_eq!(runtime.api_token(), None);
+        let mut config = Config::default();
+        config.api_token = Some(crate::adam::Secret::Plain("abc".to_string()));
+        let runtime = Runtime::start(config, PathBuf::new(), false, EventBus::new());
+        assert_eq!(runtime.api_token(), Some("abc".to_string()));
+    }
+}
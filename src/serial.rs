@@ -1,27 +1,261 @@
 use std::{
     self,
-    error::Error,
+    collections::BTreeMap,
     io,
-    sync::mpsc::Receiver,
+    sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError},
+    sync::{Arc, Mutex},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use log::*;
+use serde::Serialize;
 use serialport::prelude::*;
 use vdcp::types::ClipStatus::NoClips;
 
+use crate::events::unix_time_ms;
+use crate::logging;
 use crate::vdcp::{
     self,
-    types::{ByteNibbles, Message, PortConfig},
+    types::{ByteNibbles, ClipStatus, Message, PortConfig, PortStatus},
+    Reply,
 };
 
+///How long to wait before reopening a port that failed, doubled after each failure up to `MAX_RETRY_DELAY`
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+///A port that stayed open this long before failing is retried quickly again
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+///How long the clips are hidden from the automation after new times come in
+const CLIPS_HIDDEN_FOR: Duration = Duration::from_secs(20);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    ///Waiting to try opening the port again
+    Disconnected,
+}
+///The serial connection of one port
+#[derive(Clone, Debug, Serialize)]
+pub struct PortConnection {
+    pub device: String,
+    pub state: ConnectionState,
+    ///When the state last changed
+    pub changed_unix_ms: u64,
+    ///Why the port last failed
+    pub last_error: Option<String>,
+    ///How many times the port has been opened again after failing
+    pub reconnects: u32,
+    ///When the port will next be opened, while it is disconnected
+    pub retry_unix_ms: Option<u64>,
+}
+///The connection of every port, shared with the web server
+#[derive(Default)]
+pub struct SerialConnections {
+    ports: Mutex<BTreeMap<u8, PortConnection>>,
+}
+impl SerialConnections {
+    pub fn snapshot(&self) -> BTreeMap<u8, PortConnection> {
+        self.ports.lock().unwrap().clone()
+    }
+    pub fn remove(&self, port: u8) {
+        self.ports.lock().unwrap().remove(&port);
+    }
+    fn update(&self, port: u8, device: &str, state: ConnectionState, error: Option<String>, retry: Option<Duration>) {
+        let now = SystemTime::now();
+        let mut ports = self.ports.lock().unwrap();
+        let connection = ports.entry(port).or_insert_with(|| PortConnection {
+            device: device.to_string(),
+            state,
+            changed_unix_ms: unix_time_ms(now),
+            last_error: None,
+            reconnects: 0,
+            retry_unix_ms: None,
+        });
+        if connection.state != state {
+            connection.changed_unix_ms = unix_time_ms(now);
+        }
+        //opening counts as a reconnect if the port has failed before
+        if state == ConnectionState::Connected && connection.last_error.is_some() {
+            connection.reconnects += 1;
+        }
+        connection.device = device.to_string();
+        connection.state = state;
+        if error.is_some() {
+            connection.last_error = error;
+        }
+        connection.retry_unix_ms = retry.map(|retry| unix_time_ms(now + retry));
+    }
+}
+
+///The last vdcp command a port received
+#[derive(Clone, Debug, Serialize)]
+pub struct LastCommand {
+    ///None if the command wasn't understood
+    pub name: Option<String>,
+    pub frame: Vec<u8>,
+    pub unix_time_ms: u64,
+}
+///What a port is doing as far as the automation is concerned
+#[derive(Clone, Debug, Serialize)]
+pub struct PortActivity {
+    pub port_status: PortStatus,
+    pub clip_status: ClipStatus,
+    pub cued_clip: String,
+    ///The clip times from the web page
+    pub times: Vec<u16>,
+    ///How long until the clips are shown again after new times came in
+    pub clips_hidden_ms: Option<u64>,
+    #[serde(skip)]
+    clips_shown_at: Option<Instant>,
+    pub last_command: Option<LastCommand>,
+    ///Commands received since the port started
+    pub frames: u64,
+    pub naks: u64,
+    ///Play commands received. Other commands send events to the outputs too, but only plays are counted here
+    pub plays: u64,
+}
+///The activity of every port, shared with the web server
+#[derive(Default)]
+pub struct PortActivities {
+    ports: Mutex<BTreeMap<u8, PortActivity>>,
+}
+impl PortActivities {
+    pub fn snapshot(&self) -> BTreeMap<u8, PortActivity> {
+        let now = Instant::now();
+        let mut ports = self.ports.lock().unwrap().clone();
+        for activity in ports.values_mut() {
+            activity.clips_hidden_ms = activity
+                .clips_shown_at
+                .map(|shown| shown.saturating_duration_since(now).as_millis() as u64);
+        }
+        ports
+    }
+    pub fn remove(&self, port: u8) {
+        self.ports.lock().unwrap().remove(&port);
+    }
+    fn update(&self, config: &PortConfig, state: &ReaderState) {
+        let cued_clip = config
+            .clips
+            .get(config.cued_number as usize)
+            .map(|clip| String::from_utf8_lossy(clip).to_string())
+            .unwrap_or_default();
+        let activity = PortActivity {
+            port_status: config.port_status.clone(),
+            clip_status: config.clip_status.clone(),
+            cued_clip,
+            times: state.times.clone(),
+            clips_hidden_ms: None,
+            clips_shown_at: state.timeout.map(|hidden| hidden + CLIPS_HIDDEN_FOR),
+            last_command: state.last_command.clone(),
+            frames: state.frames,
+            naks: state.naks,
+            plays: state.plays,
+        };
+        self.ports.lock().unwrap().insert(config.configured_number, activity);
+    }
+}
+
+///What the read loop keeps between reconnects
+#[derive(Default)]
+struct ReaderState {
+    ///The clip times from the web page
+    times: Vec<u16>,
+    ///When the clips were hidden after new times came in
+    timeout: Option<Instant>,
+    last_command: Option<LastCommand>,
+    frames: u64,
+    naks: u64,
+    plays: u64,
+}
+impl ReaderState {
+    fn new() -> ReaderState {
+        ReaderState {
+            times: vec![0; 10], //todo: setting this with a random number could result in trying to access a time out of range
+            ..Default::default()
+        }
+    }
+    fn set_times(&mut self, times: Vec<u16>, config: &mut PortConfig) {
+        self.times = times;
+        self.timeout = Some(resend_times(config));
+    }
+    ///Counts a command and what was sent back for it
+    fn record(&mut self, frame: Vec<u8>, reply: &Reply) {
+        self.frames += 1;
+        if reply.is_nak() {
+            self.naks += 1;
+        }
+        if reply.command.as_deref() == Some("play") {
+            self.plays += 1;
+        }
+        self.last_command = Some(LastCommand {
+            name: reply.command.clone(),
+            frame,
+            unix_time_ms: unix_time_ms(SystemTime::now()),
+        });
+    }
+}
+
+///Runs the port until it is removed from the config, which drops the sending end of `vdcp_times`.
+///A port that fails, like a usb adapter being unplugged, is opened again with a growing delay. The port's status,
+///cued clip and clip times are kept, so the automation finds it as it left it.
 pub fn start(
     com: String,
     vdcp_times: Receiver<Vec<u16>>,
-    config: PortConfig,
-) -> Result<(), Box<dyn Error>> {
-    info!("[Port:{0}] Starting serial connection at com port:{1}",config.number, com);
+    mut config: PortConfig,
+    connections: Arc<SerialConnections>,
+    activities: Arc<PortActivities>,
+) {
+    logging::set_port(config.number);
+    let number = config.number;
+    let mut state = ReaderState::new();
+    activities.update(&config, &state);
+    let mut retry_delay = MIN_RETRY_DELAY;
+    loop {
+        info!("[Port:{0}] Starting serial connection at com port:{1}",number, com);
+        connections.update(number, &com, ConnectionState::Connecting, None, None);
+        let opened = Instant::now();
+        let result = open(&com).and_then(|port| {
+            connections.update(number, &com, ConnectionState::Connected, None, None);
+            serial_reader(port, &vdcp_times, &mut config, &mut state, &activities)
+        });
+        let e = match result {
+            Ok(()) => {
+                connections.remove(number);
+                activities.remove(number);
+                return;
+            }
+            Err(e) => e,
+        };
+        if opened.elapsed() > STABLE_AFTER {
+            retry_delay = MIN_RETRY_DELAY;
+        }
+        error!("[Port:{:}] Serial port {:} failed: {:}. Opening it again in {:?}",number, com, e, retry_delay);
+        connections.update(number, &com, ConnectionState::Disconnected, Some(e.to_string()), Some(retry_delay));
+        config.send_event(vdcp::types::EventKind::Error, None, Some(&format!("serial port failed: {:}", e)));
+        //times sent while the port is down are kept for when it is back
+        let retry_at = Instant::now() + retry_delay;
+        loop {
+            match vdcp_times.recv_timeout(retry_at.saturating_duration_since(Instant::now())) {
+                Ok(times) => {
+                    state.set_times(times, &mut config);
+                    activities.update(&config, &state);
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    info!("[Port:{:}] Port was removed while disconnected, stopping",number);
+                    connections.remove(number);
+                    activities.remove(number);
+                    return;
+                }
+            }
+        }
+        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+fn open(com: &str) -> Result<Box<dyn SerialPort>, io::Error> {
     let port_settings = serialport::SerialPortSettings {
         baud_rate: 38400,
         flow_control: FlowControl::None,
@@ -30,11 +264,7 @@ pub fn start(
         stop_bits: StopBits::One,
         timeout: Duration::from_millis(1),
     };
-    let port = serialport::open_with_settings(&com, &port_settings)?;
-
-    serial_reader(port, vdcp_times, config)?;
-
-    Ok(())
+    serialport::open_with_settings(com, &port_settings).map_err(io::Error::from)
 }
 //an end of a message can be one of two things.
 //1: a stx code(02h) denoting teh start of a new message
@@ -85,12 +315,15 @@ fn read_message(port: &mut Box<dyn SerialPort>, byte_count: u8,portNum:u8) -> Re
 fn handle_message(
     port: &mut Box<dyn SerialPort>,
     msg: Message,
-    vdcp_times: &Vec<u16>,
+    state: &mut ReaderState,
     config: &mut PortConfig,
 ) -> Result<(), io::Error> {
-    let response = vdcp::handle_command(msg, vdcp_times, config);
-    debug!("(hex)[Port:{:}] sending response : {:x?}",config.number, response);
-    port.write_all(&response)?;
+    let frame = msg.frame();
+    let _frame = logging::frame_context(&frame);
+    let reply = vdcp::handle_command(msg, &state.times, config);
+    state.record(frame, &reply);
+    debug!("(hex)[Port:{:}] sending response : {:x?}",config.number, reply.bytes);
+    port.write_all(&reply.bytes)?;
     Ok(())
 }
 ///Reads the byte_count byte from an incoming message
@@ -121,7 +354,7 @@ fn read_start(port: &mut Box<dyn SerialPort>,portNum:u8) -> Result<(), io::Error
 ///Attempts to read data from the port and then run a command associated with it
 fn handle_incoming_data(
     port: &mut Box<dyn SerialPort>,
-    vdcp_times: &Vec<u16>,
+    state: &mut ReaderState,
     config: &mut PortConfig,
 ) -> Result<(), io::Error> {
     //TODO: make it so that anything after the readstart causing a faulure sends a NAK back to the sender
@@ -137,7 +370,7 @@ fn handle_incoming_data(
         thread::sleep(std::time::Duration::from_millis(10));
     }
     let message = read_message(port, byte_count,config.number)?;
-    handle_message(port, message, vdcp_times, config)?;
+    handle_message(port, message, state, config)?;
     Ok(())
 }
 
@@ -147,61 +380,95 @@ fn resend_times(config: &mut PortConfig) -> Instant {
     Instant::now()
 
 }
+///Shows the clips again once they have been hidden long enough. True if they were
 fn check_timeout(
     timeout: &mut Option<Instant>,
     timeout_length: &Duration,
     config: &mut PortConfig,
-) {
+) -> bool {
     match timeout {
         Some(x) => {
             
             if Instant::now().duration_since(*x) > *timeout_length {
                 info!("[Port:{:}] Timeout elapsed setting clips back to 1f",config.number);
                 *timeout = None;
-                config.clip_status = vdcp::types::ClipStatus::Clips
+                config.clip_status = vdcp::types::ClipStatus::Clips;
+                return true;
             }
         }
         _ => (),
     }
+    false
 }
 
+///Publishes the end of the playing clip once its time has run out.
+///The port keeps playing, it's up to the automation to stop it
+fn check_clip_end(config: &mut PortConfig) {
+    match config.clip_ends {
+        Some(end) if Instant::now() >= end => {
+            info!("[Port:{:}] Clip reached its end",config.number);
+            config.clip_ends = None;
+            config.send_event(vdcp::types::EventKind::EndOfClip, None, None);
+        }
+        _ => (),
+    }
+}
+
+///Handles the port until it fails or is removed. Returns Ok once it is removed
 fn serial_reader(
     mut port: Box<dyn SerialPort>,
-    vdcp_times: Receiver<Vec<u16>>,
-    mut config: PortConfig,
+    vdcp_times: &Receiver<Vec<u16>>,
+    config: &mut PortConfig,
+    state: &mut ReaderState,
+    activities: &PortActivities,
 ) -> Result<(), std::io::Error> {
     info!("[Port:{:}] About to start read loop",config.number);
     //currently this just keeps reading till it finds a beginning of message command
-    let mut latest_times: Vec<u16> = vec![0; 10]; //todo: setting this with a random number could result in trying to access a time out of range
-    let mut timeout: Option<Instant> = Option::None;
-    let timeout_length = Duration::from_secs(20);
     let mut num_reads=0;
     loop {
         
-        check_timeout(&mut timeout, &timeout_length, &mut config);
+        let mut changed = check_timeout(&mut state.timeout, &CLIPS_HIDDEN_FOR, config);
+        check_clip_end(config);
 
         //we have to unwrap the thread safe atomic cell and read
-        let times = vdcp_times.try_iter();
-        match times.last() {
+        //the times channel is dropped when the port is removed from the config
+        let mut times = None;
+        loop {
+            match vdcp_times.try_recv() {
+                Ok(x) => times = Some(x),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    info!("[Port:{:}] Port was removed, stopping",config.number);
+                    return Ok(());
+                }
+            }
+        }
+        match times {
             Some(x) => {
                 let port_name = &*port.name().unwrap_or_default();
                 info!("[Port:{:}] Got new times data {:?} for port {:}",config.number, &x, port_name);
-                latest_times = x;
-                timeout=Some(resend_times(&mut config));
+                state.set_times(x, config);
+                changed = true;
             }
             _ => (),
         }
         //We limit the number of reads so we can never block the thread forever.
         while port.bytes_to_read()? !=0 &&num_reads<10{
-            match handle_incoming_data(&mut port, &latest_times, &mut config) {
+            match handle_incoming_data(&mut port, state, config) {
                 Err(e) => match e.kind() {
                     io::ErrorKind::TimedOut => continue,
-                    _ => warn!("[Port:{:}] message read failed becuase: {:}",config.number, e),
+                    _ => {
+                        warn!("[Port:{:}] message read failed becuase: {:}",config.number, e);
+                        config.send_event(vdcp::types::EventKind::Error, None, Some(&e.to_string()));
+                    }
                 },
                 Ok(_) => (),
             }
             num_reads+=1;
         }
+        if changed || num_reads > 0 {
+            activities.update(config, state);
+        }
         //Delay if we are having downtime
         if num_reads==0{
         thread::sleep(std::time::Duration::from_millis(5));
@@ -209,3 +476,349 @@ fn serial_reader(
         num_reads=0;
     }
 }
+
+//--------==================================================-----
+//=================================TESTS:======================================
+//--------==================================================-----
+
+///Integration tests that run the serial reader against one end of a pseudo terminal pair
+///and play the part of the automation system on the other end.
+#[cfg(all(test, unix))]
+mod tests {
+    use serialport::posix::TTYPort;
+    use std::io::{Read, Write};
+    use std::sync::mpsc::{sync_channel, SyncSender};
+    use test_env_log::test;
+
+    use super::*;
+    use crate::events::EventBus;
+    use crate::vdcp::types::{ClipStatus, EventKind, PortAlarms, PortEvent, PortStatus};
+    use std::sync::{atomic::Ordering, Arc};
+
+    const ACK: u8 = 0x04;
+
+    ///One running port thread and the automation end of its pty
+    struct Harness {
+        automation: TTYPort,
+        times: SyncSender<Vec<u16>>,
+        events: Receiver<PortEvent>,
+        alarms: Arc<PortAlarms>,
+        activities: Arc<PortActivities>,
+    }
+
+    impl Harness {
+        fn start(number: u8, segments: &[&str]) -> Harness {
+            let (mut automation, device) = TTYPort::pair().expect("failed creating pty pair");
+            automation
+                .set_timeout(Duration::from_millis(500))
+                .expect("failed setting pty timeout");
+            let (times, times_receiver) = sync_channel(100);
+            let bus = EventBus::new();
+            let events = bus.subscribe();
+            let alarms = Arc::new(PortAlarms::default());
+            let config = PortConfig {
+                number,
+                configured_number: number,
+                port_status: PortStatus::Idle,
+                clip_status: ClipStatus::Clips,
+                cued_number: 0,
+                clips: segments.iter().map(|a| a.as_bytes().to_vec()).collect(),
+                events: bus,
+                alarms: alarms.clone(),
+                clip_ends: None,
+                clip_left: None,
+            };
+            let activities = Arc::new(PortActivities::default());
+            let reader_activities = activities.clone();
+            thread::spawn(move || {
+                let mut config = config;
+                let mut state = ReaderState::new();
+                serial_reader(Box::new(device), &times_receiver, &mut config, &mut state, &reader_activities)
+            });
+            Harness {
+                automation,
+                times,
+                events,
+                alarms,
+                activities,
+            }
+        }
+        fn send(&mut self, command1: u8, command_code: u8, data: &[u8]) {
+            self.automation
+                .write_all(&frame(command1, command_code, data))
+                .expect("failed writing to pty");
+        }
+        fn read(&mut self, len: usize) -> Vec<u8> {
+            let mut response = vec![0u8; len];
+            self.automation
+                .read_exact(&mut response)
+                .expect("port did not respond in time");
+            response
+        }
+        fn next_event(&self) -> PortEvent {
+            self.events
+                .recv_timeout(Duration::from_secs(1))
+                .expect("port did not send an event")
+        }
+        ///The next event's kind, logical port and clip
+        fn next_event_summary(&self) -> (EventKind, u8, String) {
+            let event = self.next_event();
+            (event.kind, event.logical_port, event.clip)
+        }
+        fn expect_ack(&mut self, command1: u8, command_code: u8, data: &[u8]) {
+            self.send(command1, command_code, data);
+            assert_eq!(self.read(1), vec![ACK]);
+        }
+        ///Sends a command and checks the port replies with a message containing `reply_data`
+        fn expect_reply(&mut self, command1: u8, command_code: u8, data: &[u8], reply_data: &[u8]) {
+            let expected = reply(command1, command_code, reply_data);
+            self.send(command1, command_code, data);
+            assert_eq!(self.read(expected.len()), expected);
+        }
+        fn expect_port_status(&mut self, status: PortStatus, number: u8) {
+            self.expect_reply(0x30, 0x05, &[], &[0x05, status as u8, number, 0, 0, 0]);
+        }
+        ///The port's activity once `ready` says it has caught up. It is updated just after replying
+        fn activity(&self, number: u8, ready: impl Fn(&PortActivity) -> bool) -> PortActivity {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            loop {
+                match self.activities.snapshot().remove(&number) {
+                    Some(activity) if ready(&activity) => return activity,
+                    _ if Instant::now() > deadline => panic!("port {:} activity didn't catch up", number),
+                    _ => thread::sleep(Duration::from_millis(5)),
+                }
+            }
+        }
+    }
+
+    ///Builds a complete vdcp frame: stx, byte count, command bytes, data and checksum
+    fn frame(command1: u8, command_code: u8, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![command1, command_code];
+        body.extend_from_slice(data);
+        let checksum = body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+        let mut message = vec![0x02, body.len() as u8];
+        message.append(&mut body);
+        message.push(checksum);
+        message
+    }
+    ///The frame we expect back for a command that replies with data
+    fn reply(command1: u8, command_code: u8, data: &[u8]) -> Vec<u8> {
+        frame(command1, command_code | 0x80, data)
+    }
+    fn with_prefix(prefix: u8, data: &[u8]) -> Vec<u8> {
+        let mut res = vec![prefix];
+        res.extend_from_slice(data);
+        res
+    }
+
+    #[test]
+    fn open_port_and_status() {
+        let mut port = Harness::start(1, &["first", "second"]);
+        port.expect_reply(0x30, 0x01, &[], &[0x01]);
+        assert_eq!(port.next_event_summary(), (EventKind::Open, 1, "first".to_string()));
+        port.expect_port_status(PortStatus::Idle, 1);
+        port.expect_reply(0x30, 0x10, &[], &[0x02, 0x00, ClipStatus::Clips as u8]);
+    }
+
+    #[test]
+    fn cue_play_stop_sends_events() {
+        let mut port = Harness::start(3, &["first", "second"]);
+        port.expect_ack(0xa0, 0x25, b"AD0042");
+        port.expect_port_status(PortStatus::Cued, 3);
+        //a cue carries the clip id the automation cued, the other events the segment the port plays
+        assert_eq!(port.next_event_summary(), (EventKind::Cue, 3, "AD0042".to_string()));
+
+        port.expect_ack(0x10, 0x01, &[]);
+        let play = port.next_event();
+        assert_eq!((play.kind, play.port, play.logical_port), (EventKind::Play, 3, 3));
+        assert_eq!(play.frame, Some(frame(0x10, 0x01, &[])));
+        port.expect_port_status(PortStatus::Playing, 3);
+
+        port.expect_ack(0x10, 0x00, &[]);
+        port.expect_port_status(PortStatus::Idle, 3);
+        assert_eq!(port.next_event_summary(), (EventKind::Stop, 3, "first".to_string()));
+        assert!(port.events.try_recv().is_err(), "nothing else should be triggered");
+    }
+
+    #[test]
+    fn active_id_follows_cued_segment() {
+        let mut port = Harness::start(1, &["first", "second"]);
+        port.expect_reply(0xb0, 0x07, &[], &[0x00]);
+
+        port.expect_ack(0xa0, 0x25, b"first1");
+        port.expect_reply(0xb0, 0x07, &[], &with_prefix(0x01, b"first"));
+
+        //stopping moves on to the next segment
+        port.expect_ack(0x10, 0x01, &[]);
+        port.expect_ack(0x10, 0x00, &[]);
+        port.expect_ack(0xa0, 0x25, b"second");
+        port.expect_reply(0xb0, 0x07, &[], &with_prefix(0x01, b"second"));
+    }
+
+    #[test]
+    fn size_request_uses_times_from_web() {
+        let mut port = Harness::start(1, &["first", "second"]);
+        port.times.send(vec![90, 30]).unwrap();
+        //give the read loop a chance to pick up the new times
+        thread::sleep(Duration::from_millis(50));
+
+        port.expect_reply(0xb0, 0x14, b"first1", &[0x00, 30, 1, 0x00]);
+        port.expect_reply(0xb0, 0x14, b"second2", &[0x00, 30, 0, 0x00]);
+        //new times hide the clips until the timeout has elapsed
+        port.expect_reply(0x30, 0x10, &[], &[0x02, 0x00, ClipStatus::NoClips as u8]);
+    }
+
+    #[test]
+    fn size_request_for_missing_time_defaults_to_a_minute() {
+        let mut port = Harness::start(1, &["first"]);
+        port.expect_reply(0xb0, 0x14, b"first9", &[0x00, 0x00, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn select_port_changes_triggered_port() {
+        let mut port = Harness::start(1, &["first"]);
+        port.expect_ack(0x20, 0x22, &[7]);
+        port.expect_ack(0x10, 0x01, &[]);
+        let play = port.next_event();
+        assert_eq!((play.kind, play.port, play.logical_port), (EventKind::Play, 1, 7));
+    }
+
+    #[test]
+    fn still_holds_the_clip_end() {
+        let mut port = Harness::start(1, &["first"]);
+        port.times.send(vec![1]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        port.expect_ack(0x10, 0x01, &[]);
+        assert_eq!(port.next_event().kind, EventKind::Play);
+        port.expect_ack(0x10, 0x04, &[]);
+        assert_eq!(port.next_event_summary(), (EventKind::Still, 1, "first".to_string()));
+        port.expect_port_status(PortStatus::Still, 1);
+        //the clip can't end while it is held
+        thread::sleep(Duration::from_millis(1200));
+        assert!(port.events.try_recv().is_err());
+
+        port.expect_ack(0x10, 0x01, &[]);
+        assert_eq!(port.next_event().kind, EventKind::Play);
+        let end = port.events.recv_timeout(Duration::from_secs(2)).expect("clip did not end");
+        assert_eq!((end.kind, end.clip.as_str(), end.frame), (EventKind::EndOfClip, "first", None));
+    }
+
+    #[test]
+    fn still_before_play_doesnt_stop_the_clip_ending() {
+        let mut port = Harness::start(1, &["first"]);
+        port.times.send(vec![1]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        port.expect_ack(0xa0, 0x25, b"first1");
+        assert_eq!(port.next_event().kind, EventKind::Cue);
+        port.expect_ack(0x10, 0x04, &[]);
+        assert_eq!(port.next_event().kind, EventKind::Still);
+        port.expect_ack(0x10, 0x01, &[]);
+        assert_eq!(port.next_event().kind, EventKind::Play);
+        let end = port.events.recv_timeout(Duration::from_secs(2)).expect("clip did not end");
+        assert_eq!(end.kind, EventKind::EndOfClip);
+    }
+
+    #[test]
+    fn failed_output_shows_in_port_status() {
+        let mut port = Harness::start(2, &["first"]);
+        port.alarms.output_failed.store(true, Ordering::Relaxed);
+        port.expect_reply(0x30, 0x05, &[], &[0x05, PortStatus::Idle as u8, 2, 0x80, 0, 0]);
+        port.alarms.output_failed.store(false, Ordering::Relaxed);
+        port.expect_port_status(PortStatus::Idle, 2);
+    }
+
+    #[test]
+    fn port_status_follows_tally() {
+        let mut port = Harness::start(2, &["first"]);
+        port.alarms.set_tally(Some(true));
+        port.expect_port_status(PortStatus::Playing, 2);
+        port.expect_ack(0x10, 0x01, &[]);
+        port.alarms.set_tally(Some(false));
+        port.expect_port_status(PortStatus::Idle, 2);
+    }
+
+    #[test]
+    fn unknown_command_is_nakked() {
+        let mut port = Harness::start(1, &["first"]);
+        port.send(0x30, 0x7f, &[]);
+        assert_eq!(port.read(2), vec![0x05, 0x01]);
+        let error = port.next_event();
+        assert_eq!(error.kind, EventKind::Error);
+        assert_eq!(error.detail.as_deref(), Some("unknown command 30 7f"));
+        //the port keeps working after a nak
+        port.expect_reply(0x30, 0x01, &[], &[0x01]);
+    }
+
+    #[test]
+    fn activity_counts_commands() {
+        let mut port = Harness::start(5, &["first", "second"]);
+        port.expect_ack(0xa0, 0x25, b"second1");
+        port.expect_ack(0x10, 0x01, &[]);
+        port.send(0x30, 0x7f, &[]);
+        assert_eq!(port.read(2), vec![0x05, 0x01]);
+        let activity = port.activity(5, |activity| activity.frames == 3);
+        assert_eq!((activity.frames, activity.naks, activity.plays), (3, 1, 1));
+        assert_eq!(activity.port_status, PortStatus::Playing);
+        //cueing doesn't pick the segment, the port plays its segments in turn
+        assert_eq!(activity.cued_clip, "first");
+        let last = activity.last_command.unwrap();
+        assert_eq!(last.name, None);
+        assert_eq!(last.frame, frame(0x30, 0x7f, &[]));
+
+        port.times.send(vec![30, 40]).unwrap();
+        port.expect_ack(0x10, 0x00, &[]);
+        let activity = port.activity(5, |activity| activity.frames == 4 && activity.times == vec![30, 40]);
+        assert_eq!(activity.port_status, PortStatus::Idle);
+        assert_eq!(activity.cued_clip, "second");
+        assert_eq!(activity.clip_status, ClipStatus::NoClips);
+        assert!(activity.clips_hidden_ms.unwrap() <= CLIPS_HIDDEN_FOR.as_millis() as u64);
+        assert_eq!(activity.last_command.unwrap().name.as_deref(), Some("stop"));
+    }
+
+    #[test]
+    fn garbage_before_frame_is_skipped() {
+        let mut port = Harness::start(1, &["first"]);
+        port.automation.write_all(&[0xff, 0x13]).unwrap();
+        port.expect_reply(0x30, 0x01, &[], &[0x01]);
+    }
+
+    #[test]
+    fn failed_port_is_retried_until_removed() {
+        let (times, receiver) = sync_channel(100);
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+        let config = PortConfig {
+            number: 4,
+            configured_number: 4,
+            port_status: PortStatus::Idle,
+            clip_status: ClipStatus::Clips,
+            cued_number: 0,
+            clips: vec![b"first".to_vec()],
+            events: bus,
+            alarms: Arc::new(PortAlarms::default()),
+            clip_ends: None,
+            clip_left: None,
+        };
+        let connections = Arc::new(SerialConnections::default());
+        let activities = Arc::new(PortActivities::default());
+        let supervisor = {
+            let (connections, activities) = (connections.clone(), activities.clone());
+            thread::spawn(move || start("/dev/vdcp-spoof-missing".to_string(), receiver, config, connections, activities))
+        };
+        let failed = events.recv_timeout(Duration::from_secs(1)).expect("the failure should be published");
+        assert_eq!(failed.kind, EventKind::Error);
+        let connection = connections.snapshot()[&4].clone();
+        assert_eq!(connection.state, ConnectionState::Disconnected);
+        assert!(connection.last_error.is_some());
+        assert!(connection.retry_unix_ms.is_some());
+
+        //times sent while it is down are kept, and removing the port doesn't wait for the retry
+        times.send(vec![10]).unwrap();
+        drop(times);
+        let removed = Instant::now();
+        supervisor.join().unwrap();
+        assert!(removed.elapsed() < MIN_RETRY_DELAY);
+        assert!(connections.snapshot().is_empty());
+        assert!(activities.snapshot().is_empty());
+    }
+}
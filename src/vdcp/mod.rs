@@ -100,7 +100,8 @@ fn post_processing(message: &Message,  response: Response) -> Vec<u8> {
     }
 }
 
-fn run_command(message: &Message, commands: &[Command], clip_times: &Vec<u16>,config:&mut PortConfig) -> Response {
+///Runs the command the message is for. Returns its name, or None if it isn't one we know, with its response
+fn run_command(message: &Message, commands: &[Command], clip_times: &Vec<u16>,config:&mut PortConfig) -> (Option<String>, Response) {
     for command in commands {
         //we have to use an unsafe block because we access a union to get our nibbles from a byte
         unsafe {
@@ -111,14 +112,27 @@ fn run_command(message: &Message, commands: &[Command], clip_times: &Vec<u16>,co
                 let func = &*command.action;
 
                 let a = func(&message, clip_times,config);
-              return a;
+              return (Some(command.name.clone()), a);
             }
         }
     }
-    unknown_command(message,config.number)
+    (None, unknown_command(message,config))
 }
 
-pub fn handle_command(msg: Message, clip_times: &Vec<u16>,config:&mut PortConfig) -> Vec<u8> {
+///What was sent back for a command
+pub struct Reply {
+    ///The name of the command, None if it wasn't understood
+    pub command: Option<String>,
+    pub bytes: Vec<u8>,
+}
+impl Reply {
+    pub fn is_nak(&self) -> bool {
+        self.bytes.first() == Some(&NAK)
+    }
+}
+const NAK: u8 = 0x05;
+
+pub fn handle_command(msg: Message, clip_times: &Vec<u16>,config:&mut PortConfig) -> Reply {
 
     unsafe {
         debug!(
@@ -135,7 +149,7 @@ pub fn handle_command(msg: Message, clip_times: &Vec<u16>,config:&mut PortConfig
     }
 
     let commands = responses::get_commands();
-    let return_data = run_command(&msg, &commands, clip_times,config);
+    let (command, return_data) = run_command(&msg, &commands, clip_times,config);
     let return_message = post_processing(&msg, return_data);
-    return return_message;
+    Reply { command, bytes: return_message }
 }
@@ -2,6 +2,8 @@ use std::{error::Error, str::from_utf8};
 
 use super::types::*;
 use log::*;
+///Set in the port status when something the port triggered has failed
+const PORT_ERROR: u8 = 0x80;
 fn simp(data: Vec<u8>) -> Response {
     Response::Simple(data)
 }
@@ -9,12 +11,27 @@ fn msg(data: Vec<u8>) -> Response {
     Response::Message(data)
 }
 
-fn play(_: &Message, _: &Vec<u16>, config: &mut PortConfig) -> Response {
+fn play(message: &Message, clip_times: &Vec<u16>, config: &mut PortConfig) -> Response {
     info!("Playing port {:}",config.number);
-    config.play_sender.send(config.number).unwrap_or_else(|_|{error!("Could not send play command to adam. channel not working")});//sends the play command with this ports number
+    //playing out of a still carries on with the time the clip had left.
+    //A still sent before the clip was played held nothing, so the clip starts from the beginning
+    match config.port_status {
+        PortStatus::Still if config.clip_left.is_some() => config.resume_clip(),
+        _ => config.start_clip(clip_times),
+    }
+    config.send_event(EventKind::Play, Some(message), None);//sends the play command with this ports number
     config.port_status = PortStatus::Playing;
     simp(vec![0x04])
 }
+fn still(message: &Message, _: &Vec<u16>, config: &mut PortConfig) -> Response {
+    info!("Holding still on port {:}",config.number);
+    config.send_event(EventKind::Still, Some(message), None);
+    if let PortStatus::Playing = config.port_status {
+        config.hold_clip();
+    }
+    config.port_status = PortStatus::Still;
+    simp(vec![0x04])
+}
 fn active_id(_: &Message, _: &Vec<u16>, config: &mut PortConfig) -> Response {
     match config.port_status {
         PortStatus::Idle => return msg(vec![0x0]),
@@ -25,14 +42,18 @@ fn active_id(_: &Message, _: &Vec<u16>, config: &mut PortConfig) -> Response {
         }
     }
 }
-fn stop(_: &Message, _: &Vec<u16>, config: &mut PortConfig) -> Response {
+fn stop(message: &Message, _: &Vec<u16>, config: &mut PortConfig) -> Response {
+    config.send_event(EventKind::Stop, Some(message), None);
     config.port_status = PortStatus::Idle;
+    config.clip_ends = None;
+    config.clip_left = None;
     config.next_clip();
     simp(vec![0x04])
 }
 fn size_request(message: &Message, clip_times: &Vec<u16>, config: &mut PortConfig) -> Response {
     let clip_name = from_utf8(&message.data).unwrap_or("failed to convert from bytes to utf8");
     info!("[Port: {:}]size requested for clip {:?}",config.number, clip_name);
+    config.send_event(EventKind::SizeRequest, Some(message), Some(clip_name));
     let stuff = || -> Result<Response, Box<dyn Error>> {
         //the last data byte should tell us the clip number as a utf8 byte
         let last = message.data.last().ok_or("data was empty")?;
@@ -57,12 +78,14 @@ fn size_request(message: &Message, clip_times: &Vec<u16>, config: &mut PortConfi
         msg(vec![0x0, 0x0, 0x1, 0x0])
     })
 }
-pub fn unknown_command(msg: &Message,portNum:u8) -> Response {
+pub fn unknown_command(msg: &Message,config: &mut PortConfig) -> Response {
     unsafe {
         warn!(
-            "[Port: {:}](hex)received unknown command|{:x?}|{:x?}|{:x?}|{:x?}|{:x?}|",portNum,
+            "[Port: {:}](hex)received unknown command|{:x?}|{:x?}|{:x?}|{:x?}|{:x?}|",config.number,
             msg.byte_count, msg.command1.byte, msg.command_code, msg.data, msg.checksum
         );
+        let detail = format!("unknown command {:x?} {:x?}", msg.command1.byte, msg.command_code);
+        config.send_event(EventKind::Error, Some(msg), Some(&detail));
     }
     simp(vec![0x05, 0x1])
 }
@@ -72,7 +95,10 @@ pub fn get_commands() -> Vec<Command> {
         msg(vec![0x02, 0x00, conf.clip_status.clone() as u8])
     }); //?NOTE: The return here is the number of ids stored by the vdcp server. i think it can remain constant and simply be the max number of clips we ever have
 
-    let open_port: Command = Command::new("open_port", 0x3, 0x01, |_, _, _| (msg(vec![0x01]))); // opened:01 denied:00
+    let open_port: Command = Command::new("open_port", 0x3, 0x01, |message, _, config| {
+        config.send_event(EventKind::Open, Some(message), None);
+        msg(vec![0x01])
+    }); // opened:01 denied:00
     let close_port: Command = Command::new("close_port", 0x2, 0x21, |_, _, _| (simp(vec![0x04]))); // opened:01 denied:00
 
     let port_status: Command = Command::new("port_status", 0x3, 0x05, |_, _, config| {
@@ -81,11 +107,17 @@ pub fn get_commands() -> Vec<Command> {
         //vec![0x5, 0x01, 0x01, 0x0, 0x0, 0x0]   | port one selected and idle
         //s1,2 is the port number
         //|bitmap|s1,1|s1,2|s3,1| ,2 |  ,3|
+        //A tally input overrides what the play and stop commands said
+        let status = match (config.alarms.tally(), &config.port_status) {
+            (Some(true), _) => PortStatus::Playing,
+            (Some(false), PortStatus::Playing) => PortStatus::Idle,
+            (_, status) => status.clone(),
+        };
         msg(vec![
             0x5,
-            config.port_status.clone() as u8,
+            status as u8,
             config.number,
-            0x0,
+            if config.alarms.any() { PORT_ERROR } else { 0x0 },
             0x0,
             0x0,
         ])
@@ -101,12 +133,12 @@ pub fn get_commands() -> Vec<Command> {
         simp(vec![0x04])
     }); //?NOTE this selects a specific port for playing
     let cue_with_data: Command = Command::new("cue_with_data", 0xa, 0x25, |msg, _, config| {
-        info!(
-            "[Port:{:}]Cueing clip: {:}",
-            config.number,
-            std::str::from_utf8(&msg.data[0..6]).unwrap_or("")
-        );
+        //the clip id the automation cued, which is what outputs want rather than our own segment
+        let id = String::from_utf8_lossy(msg.data.get(0..6).unwrap_or(&msg.data));
+        let id = id.trim_end_matches(|c| c == ' ' || c == '\0');
+        info!("[Port:{:}]Cueing clip: {:}", config.number, id);
         config.port_status = PortStatus::Cued;
+        config.send_clip_event(EventKind::Cue, id, Some(msg), None);
         simp(vec![0x04])
     }); //the data is discarded because we don't need to cue
     let active_id_request: Command = Command::new("active_id_request", 0x0b, 0x07, active_id);
@@ -115,6 +147,7 @@ pub fn get_commands() -> Vec<Command> {
     }); //TODO: i need to find out what this command is for
     let play: Command = Command::new("play", 0x1, 0x01, play); 
     let stop: Command = Command::new("stop", 0x1, 0x00, stop); 
+    let still: Command = Command::new("still", 0x1, 0x04, still);
     let id_request: Command = Command::new("id_request", 0xb, 0x16, |message, _, config| {
         match String::from_utf8(message.data.clone()) {
             Ok(a) => info!("[Port:{:}]Got ID request for file : {:}",config.number, a),
@@ -135,6 +168,7 @@ pub fn get_commands() -> Vec<Command> {
         play,
         close_port,
         stop,
+        still,
     ];
     return commands;
     /*
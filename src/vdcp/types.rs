@@ -1,4 +1,11 @@
 use modular_bitfield::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
 #[bitfield]
 #[derive(Clone, Copy)]
 pub struct Nibbles {
@@ -24,28 +31,152 @@ pub struct Message {
     pub checksum: u8,
     pub data: Vec<u8>,
 }
+impl Message {
+    ///The message as it came in: stx, byte count, command bytes, data and checksum
+    pub fn frame(&self) -> Vec<u8> {
+        let mut frame = vec![0x02, self.byte_count, unsafe { self.command1.byte }, self.command_code];
+        frame.extend_from_slice(&self.data);
+        frame.push(self.checksum);
+        frame
+    }
+}
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum PortStatus {
     Idle = 0x01,
     Cued = 0x80,
     Playing = 0x04,
+    Still = 0x08,
 }
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum ClipStatus {
     Clips = 0x1f,
     NoClips = 0x00,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Play,
+    Stop,
+    Cue,
+    ///The automation paused the clip on its current frame
+    Still,
+    ///The cued clip has played for as long as its time from the web page
+    EndOfClip,
+    ///The automation opened the port
+    Open,
+    SizeRequest,
+    ///A command couldn't be read or wasn't understood
+    Error,
+}
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Play => "play",
+            EventKind::Stop => "stop",
+            EventKind::Cue => "cue",
+            EventKind::Still => "still",
+            EventKind::EndOfClip => "end_of_clip",
+            EventKind::Open => "open",
+            EventKind::SizeRequest => "size_request",
+            EventKind::Error => "error",
+        }
+    }
+}
+///Something that happened on a port, published on the event bus
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PortEvent {
+    pub kind: EventKind,
+    ///The number of the serial port in the config
+    pub port: u8,
+    ///The port the automation has selected, which is what outputs are mapped by.
+    ///The same as `port` unless the automation selects another
+    pub logical_port: u8,
+    ///The clip cued on the port when it happened
+    pub clip: String,
+    ///What went wrong for errors and the clip asked about for size requests
+    pub detail: Option<String>,
+    ///How long the playing clip has left, if its time is known
+    pub clip_remaining_ms: Option<u64>,
+    #[serde(rename = "unix_time_ms", serialize_with = "crate::events::serialize_unix_ms")]
+    pub time: SystemTime,
+    ///The vdcp message that caused the event, if there was one
+    pub frame: Option<Vec<u8>>,
+}
+impl PortEvent {
+    pub fn new(kind: EventKind, port: u8, clip: &str) -> PortEvent {
+        PortEvent {
+            kind,
+            port,
+            logical_port: port,
+            clip: clip.to_string(),
+            detail: None,
+            clip_remaining_ms: None,
+            time: SystemTime::now(),
+            frame: None,
+        }
+    }
+    pub fn with_detail(mut self, detail: &str) -> PortEvent {
+        self.detail = Some(detail.to_string());
+        self
+    }
+}
+
+///Problems other threads raise against a port. They are reported back in the port's status
+#[derive(Debug, Default)]
+pub struct PortAlarms {
+    ///The last output triggered for this port failed
+    pub output_failed: AtomicBool,
+    ///The tally didn't come on in time after the port was played
+    pub tally_missing: AtomicBool,
+    ///The tally the port's status follows. 0 when there is none, otherwise 1 for off and 2 for on
+    tally: AtomicU8,
+}
+impl PortAlarms {
+    pub fn any(&self) -> bool {
+        self.output_failed.load(Ordering::Relaxed) || self.tally_missing.load(Ordering::Relaxed)
+    }
+    ///Whether the port is on air according to its tally input, if its status follows one
+    pub fn tally(&self) -> Option<bool> {
+        match self.tally.load(Ordering::Relaxed) {
+            0 => None,
+            value => Some(value == 2),
+        }
+    }
+    pub fn set_tally(&self, on: Option<bool>) {
+        let value = match on {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        };
+        self.tally.store(value, Ordering::Relaxed);
+    }
+}
+///The alarms of each port by port number
+pub type PortAlarmMap = HashMap<u8, Arc<PortAlarms>>;
+
+use crate::events::EventBus;
 pub struct PortConfig {
+    ///The port number the automation has selected
     pub number: u8,
+    ///The port number given in the config. Doesn't change when the automation selects a port
+    pub configured_number: u8,
     pub port_status: PortStatus,
     pub clip_status: ClipStatus,
     pub cued_number: u8,
     pub clips: Vec<Vec<u8>>,
-    pub play_sender:std::sync::mpsc::Sender<u8>
+    ///Where everything that happens on the port is published
+    pub events: EventBus,
+    pub alarms: Arc<PortAlarms>,
+    ///When the playing clip runs out, if its time is known
+    pub clip_ends: Option<Instant>,
+    ///How much of the clip was left when it was held on a still
+    pub clip_left: Option<Duration>,
 }
 impl PortConfig {
     ///Moves the cued number index to the next clip in clips
@@ -57,6 +188,47 @@ impl PortConfig {
             self.cued_number = 0;
         }
     }
+    ///Works out when the cued clip will end if it starts playing now.
+    ///Clips without a time from the web page never end on their own
+    pub fn start_clip(&mut self, clip_times: &[u16]) {
+        self.clip_ends = clip_times
+            .get(self.cued_number as usize)
+            .filter(|seconds| **seconds != 0)
+            .map(|seconds| Instant::now() + Duration::from_secs(*seconds as u64));
+        self.clip_left = None;
+    }
+    ///Stops the clip end counting down while the port is held on a still
+    pub fn hold_clip(&mut self) {
+        let now = Instant::now();
+        self.clip_left = self.clip_ends.take().map(|end| end.saturating_duration_since(now));
+    }
+    pub fn resume_clip(&mut self) {
+        self.clip_ends = self.clip_left.take().map(|left| Instant::now() + left);
+    }
+    ///Publishes something that happened on this port. `message` is the command that caused it
+    pub fn send_event(&self, kind: EventKind, message: Option<&Message>, detail: Option<&str>) {
+        let clip = self
+            .clips
+            .get(self.cued_number as usize)
+            .map(|clip| String::from_utf8_lossy(clip).to_string())
+            .unwrap_or_default();
+        self.send_clip_event(kind, &clip, message, detail);
+    }
+    ///Publishes an event about `clip` rather than the segment the port has cued, like the clip id of a cue
+    pub fn send_clip_event(&self, kind: EventKind, clip: &str, message: Option<&Message>, detail: Option<&str>) {
+        self.events.publish(PortEvent {
+            kind,
+            port: self.configured_number,
+            logical_port: self.number,
+            clip: clip.to_string(),
+            detail: detail.map(str::to_string),
+            clip_remaining_ms: self
+                .clip_ends
+                .map(|end| end.saturating_duration_since(Instant::now()).as_millis() as u64),
+            time: SystemTime::now(),
+            frame: message.map(Message::frame),
+        });
+    }
     ///Gets the current cued clip
     pub fn get_cued_clip(&mut self) -> Vec<u8> {
         self.clips[self.cued_number as usize].clone()
@@ -1,35 +1,94 @@
 #![feature(proc_macro_hygiene, decl_macro)]
-use mpsc::SyncSender;
-
-use super::config::Config;
-use log::{error, info};
-use rocket::{State, response::NamedFile};
+use super::adam::dispatch::{DispatchStats, QueueStats};
+use super::adam::health::{AdamHealth, ModuleHealth};
+use super::adam::outcomes::{LatencySummary, TriggerLog, TriggerOutcome};
+use super::adam::{AdamCommand, AdamModule, Secret};
+use super::cli::Options;
+use super::config::{Config, ConfigDiff, VDCPPort};
+use super::events::RecentEvents;
+use super::outputs::OutputConfig;
+use super::runtime::Runtime;
+use super::serial::{PortActivity, PortConnection};
+use super::vdcp::types::PortEvent;
+use log::{error, info, warn};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::status::BadRequest;
+use rocket::{Outcome, State, response::NamedFile};
 use rocket_contrib::json::Json;
 use rocket_cors::CorsOptions;
 use serde::{Deserialize, Serialize};
-use std::{self, collections::HashMap, io, path::{Path, PathBuf}, sync::mpsc::{self}};
+use std::{self, collections::{BTreeMap, HashMap}, io, path::PathBuf, sync::Arc};
 
 #[derive(Deserialize, Serialize)]
 struct VDCPTimes {
     pub times: HashMap<u8, Vec<u16>>,
 }
 
-pub type TimesUpdaters = Vec<SyncSender<Vec<u16>>>;
+#[derive(Serialize)]
+struct TriggerReport {
+    ///Ports whose last trigger failed
+    failed_ports: Vec<u8>,
+    ///Frame to module latency of the outcomes below
+    latency: LatencySummary,
+    ///Newest first
+    outcomes: Vec<TriggerOutcome>,
+}
+
+///Everything known about one port, for the status page
+#[derive(Serialize)]
+struct PortReport {
+    name: String,
+    ///Whether its serial device is open
+    connection: Option<PortConnection>,
+    ///What the automation has done with it, once its thread has started
+    activity: Option<PortActivity>,
+}
+
+///Where the web page's files are served from
+struct StaticDir(PathBuf);
+///The api url given to the web page, if it isn't where the page was loaded from
+struct PublicUrl(Option<String>);
+
+///The host a request was sent to
+struct Host(Option<String>);
+impl<'a, 'r> FromRequest<'a, 'r> for Host {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        Outcome::Success(Host(request.headers().get_one("Host").map(str::to_string)))
+    }
+}
+#[derive(Serialize)]
+struct UiConfig {
+    #[serde(rename = "VDCPSpoofUrl")]
+    vdcp_spoof_url: String,
+}
 
 #[get("/<file..>")]
-fn files(file: PathBuf) -> Option<NamedFile> {
-    NamedFile::open(Path::new("public/").join(file)).ok()
+fn files(file: PathBuf, dir: State<StaticDir>) -> Option<NamedFile> {
+    NamedFile::open(dir.0.join(file)).ok()
 }
 #[get("/")]
-fn index() -> io::Result<NamedFile> {
-    NamedFile::open("public/index.html")
+fn index(dir: State<StaticDir>) -> io::Result<NamedFile> {
+    NamedFile::open(dir.0.join("index.html"))
+}
+///Tells the web page where the api is. Unless a public url is set that is the address the page was loaded from
+#[get("/config.json")]
+fn ui_config(public_url: State<PublicUrl>, host: Host) -> Json<UiConfig> {
+    let url = match (&public_url.0, host.0) {
+        (Some(url), _) => url.clone(),
+        (None, Some(host)) => format!("http://{:}", host),
+        //an empty url makes the page use relative paths
+        (None, None) => String::new(),
+    };
+    Json(UiConfig { vdcp_spoof_url: url })
 }
 #[put("/api/times", data = "<vdcp_times>")]
-fn times(times_db: State<TimesUpdaters>, vdcp_times: Json<VDCPTimes>) -> &'static str {
+fn times(runtime: State<Arc<Runtime>>, vdcp_times: Json<VDCPTimes>) -> &'static str {
     for time in &vdcp_times.times {
         let i: usize = time.0.clone().into();
 
-        match times_db[i].send(time.1.clone()) {
+        match runtime.send_times(i, time.1.clone()) {
             Err(e) => {
                 error!("Failed sending times to thread {:}", e)
             }
@@ -40,12 +99,192 @@ fn times(times_db: State<TimesUpdaters>, vdcp_times: Json<VDCPTimes>) -> &'stati
     "set data"
 }
 #[get("/api/ports")]
-fn ports(conf: State<Config>) -> Json<Config> {
+fn ports(runtime: State<Arc<Runtime>>) -> Json<Config> {
     info_!("got request for ports");
-    Json(conf.clone())
+    Json(runtime.config().redacted())
+}
+
+///Whether each port's serial device is open, and why it last failed
+#[get("/api/ports/connections")]
+fn connections(runtime: State<Arc<Runtime>>) -> Json<BTreeMap<u8, PortConnection>> {
+    Json(runtime.connections.snapshot())
+}
+
+///What each port is doing, keyed by the port number in the config
+#[get("/api/status")]
+fn status(runtime: State<Arc<Runtime>>) -> Json<BTreeMap<u8, PortReport>> {
+    let mut connections = runtime.connections.snapshot();
+    let mut activities = runtime.activities.snapshot();
+    let ports = runtime.config().ports;
+    Json(
+        ports
+            .into_iter()
+            .map(|port| {
+                let report = PortReport {
+                    name: port.name,
+                    connection: connections.remove(&port.number),
+                    activity: activities.remove(&port.number),
+                };
+                (port.number, report)
+            })
+            .collect(),
+    )
+}
+
+#[get("/api/adam/triggers")]
+fn triggers(log: State<Arc<TriggerLog>>, runtime: State<Arc<Runtime>>) -> Json<TriggerReport> {
+    let mut failed_ports: Vec<u8> = runtime
+        .alarms()
+        .iter()
+        .filter(|(_, alarms)| alarms.any())
+        .map(|(port, _)| *port)
+        .collect();
+    failed_ports.sort();
+    Json(TriggerReport {
+        failed_ports,
+        latency: log.latency(),
+        outcomes: log.recent(),
+    })
+}
+
+///Whether each adam module is answering, keyed by module id
+#[get("/api/adam")]
+fn adam_health(health: State<Arc<AdamHealth>>) -> Json<BTreeMap<u8, ModuleHealth>> {
+    Json(health.snapshot())
+}
+
+///The request queue of each adam module, keyed by module id
+#[get("/api/adam/queues")]
+fn adam_queues(queues: State<Arc<DispatchStats>>) -> Json<BTreeMap<u8, QueueStats>> {
+    Json(queues.snapshot())
+}
+
+///The most recent events from every port, newest first
+#[get("/api/events")]
+fn events(recent: State<Arc<RecentEvents>>) -> Json<Vec<PortEvent>> {
+    Json(recent.recent())
+}
+
+//===Config editing===
+//Every change is applied straight away, restarting only what it touches, and written back to the config file.
+//The file is written out from the settings, so any comments in it are lost.
+
+///A request that carried the `api_token` from the config as a bearer token
+struct ApiToken;
+impl<'a, 'r> FromRequest<'a, 'r> for ApiToken {
+    type Error = &'static str;
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let runtime = match request.guard::<State<Arc<Runtime>>>() {
+            Outcome::Success(runtime) => runtime,
+            _ => return Outcome::Failure((Status::InternalServerError, "runtime isn't available")),
+        };
+        let expected = match runtime.api_token() {
+            Some(token) => format!("Bearer {:}", token),
+            None => return Outcome::Failure((Status::Forbidden, "config editing is turned off, set api_token")),
+        };
+        match request.headers().get_one("Authorization") {
+            Some(given) if given == expected => Outcome::Success(ApiToken),
+            _ => {
+                warn!("Rejected config change from {:?} with a missing or wrong token", request.client_ip());
+                Outcome::Failure((Status::Unauthorized, "missing or wrong api token"))
+            }
+        }
+    }
+}
+type EditResult = Result<Json<ConfigDiff>, BadRequest<String>>;
+fn edit(runtime: &Runtime, change: impl FnOnce(&mut Config) -> Result<(), String>) -> EditResult {
+    runtime.edit(change).map(Json).map_err(|e| {
+        warn!("Config change was refused: {:}", e);
+        BadRequest(Some(e))
+    })
+}
+fn missing(what: &str, key: u8) -> String {
+    format!("{:} {:} isn't in the config", what, key)
+}
+
+///Adds a port or replaces its settings
+#[put("/api/config/ports/<number>", data = "<port>")]
+fn put_port(_token: ApiToken, runtime: State<Arc<Runtime>>, number: u8, port: Json<VDCPPort>) -> EditResult {
+    edit(&runtime, |config| {
+        let port = VDCPPort { number, ..port.into_inner() };
+        match config.ports.iter_mut().find(|p| p.number == number) {
+            Some(existing) => *existing = port,
+            None => config.ports.push(port),
+        }
+        Ok(())
+    })
+}
+#[delete("/api/config/ports/<number>")]
+fn delete_port(_token: ApiToken, runtime: State<Arc<Runtime>>, number: u8) -> EditResult {
+    edit(&runtime, |config| {
+        let before = config.ports.len();
+        config.ports.retain(|p| p.number != number);
+        if config.ports.len() == before {
+            return Err(missing("port", number));
+        }
+        Ok(())
+    })
+}
+#[put("/api/config/ports/<number>/segments", data = "<segments>")]
+fn put_segments(_token: ApiToken, runtime: State<Arc<Runtime>>, number: u8, segments: Json<Vec<String>>) -> EditResult {
+    edit(&runtime, |config| {
+        let port = config
+            .ports
+            .iter_mut()
+            .find(|p| p.number == number)
+            .ok_or_else(|| missing("port", number))?;
+        port.segments = segments.into_inner();
+        Ok(())
+    })
+}
+///Adds or replaces a module. A password of `***`, as shown by `/api/ports`, keeps the current one
+#[put("/api/config/adam_modules/<id>", data = "<module>")]
+fn put_adam_module(_token: ApiToken, runtime: State<Arc<Runtime>>, id: u8, module: Json<AdamModule>) -> EditResult {
+    edit(&runtime, |config| {
+        let mut module = module.into_inner();
+        if let (Secret::Plain(password), Some(existing)) = (&module.password, config.adam_modules.get(&id)) {
+            if password == "***" {
+                module.password = existing.password.clone();
+            }
+        }
+        config.adam_modules.insert(id, module);
+        Ok(())
+    })
+}
+#[delete("/api/config/adam_modules/<id>")]
+fn delete_adam_module(_token: ApiToken, runtime: State<Arc<Runtime>>, id: u8) -> EditResult {
+    edit(&runtime, |config| {
+        config.adam_modules.remove(&id).map(|_| ()).ok_or_else(|| missing("adam module", id))
+    })
+}
+#[put("/api/config/port_outputs/<number>", data = "<outputs>")]
+fn put_port_outputs(_token: ApiToken, runtime: State<Arc<Runtime>>, number: u8, outputs: Json<Vec<OutputConfig>>) -> EditResult {
+    edit(&runtime, |config| {
+        config.port_outputs.insert(number, outputs.into_inner());
+        Ok(())
+    })
+}
+#[delete("/api/config/port_outputs/<number>")]
+fn delete_port_outputs(_token: ApiToken, runtime: State<Arc<Runtime>>, number: u8) -> EditResult {
+    edit(&runtime, |config| {
+        config.port_outputs.remove(&number).map(|_| ()).ok_or_else(|| missing("port outputs for port", number))
+    })
+}
+#[put("/api/config/adam_output_mapping/<number>", data = "<command>")]
+fn put_adam_output(_token: ApiToken, runtime: State<Arc<Runtime>>, number: u8, command: Json<AdamCommand>) -> EditResult {
+    edit(&runtime, |config| {
+        config.adam_output_mapping.insert(number, command.into_inner());
+        Ok(())
+    })
+}
+#[delete("/api/config/adam_output_mapping/<number>")]
+fn delete_adam_output(_token: ApiToken, runtime: State<Arc<Runtime>>, number: u8) -> EditResult {
+    edit(&runtime, |config| {
+        config.adam_output_mapping.remove(&number).map(|_| ()).ok_or_else(|| missing("adam output for port", number))
+    })
 }
 
-pub fn start_server(config: Config, times_db: TimesUpdaters) -> rocket::Rocket {
+pub fn start_server(runtime: Arc<Runtime>, recent_events: Arc<RecentEvents>, options: &Options) -> Result<rocket::Rocket, String> {
     let mut times = VDCPTimes {
         times: HashMap::new(),
     };
@@ -59,10 +298,34 @@ pub fn start_server(config: Config, times_db: TimesUpdaters) -> rocket::Rocket {
     }
     .to_cors()
     .expect("failed making cors options");
-    let a = rocket::ignite()
-        .mount("/", routes![index, times, ports,files])
-        .manage(times_db)
-        .manage(config)
+    let mut config = rocket::Config::active().map_err(|e| e.to_string())?;
+    config
+        .set_address(options.address.clone())
+        .map_err(|e| format!("{:} isn't an address that can be listened on: {:}", options.address, e))?;
+    config.set_port(options.port);
+    let a = rocket::custom(config)
+        .mount("/", routes![index, ui_config, times, ports, connections, status, triggers, adam_health, adam_queues, events, files])
+        .mount(
+            "/",
+            routes![
+                put_port,
+                delete_port,
+                put_segments,
+                put_adam_module,
+                delete_adam_module,
+                put_port_outputs,
+                delete_port_outputs,
+                put_adam_output,
+                delete_adam_output
+            ],
+        )
+        .manage(runtime.trigger_log.clone())
+        .manage(runtime.adam_health.clone())
+        .manage(runtime.adam_queues.clone())
+        .manage(runtime)
+        .manage(recent_events)
+        .manage(StaticDir(options.static_dir.clone()))
+        .manage(PublicUrl(options.public_url.clone()))
         .attach(cors_opts);
-    a
+    Ok(a)
 }